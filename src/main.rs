@@ -1,27 +1,119 @@
 use std::{
     sync::{
         atomic::AtomicBool,
-        mpsc::{self, Receiver, Sender},
+        mpsc::{self, Receiver},
         Arc,
     },
     thread,
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
 use rusty_bridge_lib::{
-    vtspc::VtsPc,
-    vtsphone::{TrackingResponce, VtsPhone},
+    config::{BridgeCfg, SourceCfg},
+    health::{PhoneEvent, PhoneState, VtsEvent, VtsState},
+    merge::Merger,
+    phoneserver::PhoneEmulator,
+    pipeline::Pipeline,
+    relay::Relay,
+    sink::{run_sink, OutputCfg, SinkCfg, DEFAULT_OSC_PREFIX},
+    slot::slot,
+    transform::Transform,
+    vrchat::DEFAULT_VRCHAT_TARGET,
+    vtspc::{VtsCfg, VtsPc},
+    vtsphone::{PhoneSettings, VtsPhone},
 };
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Path to json file with transformation config
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Path to json file with transformation config, not sending to VTubeStudio if not set
     #[arg(short, long)]
-    transform_cfg: String,
-    /// Set phone ip
+    transform_cfg: Option<String>,
+    /// Path to json file with bridge config (inputs)
     #[arg(short, long)]
-    phone_ip: String,
+    config: Option<String>,
+    /// Set phone ip or hostname, `auto` for first phone found on LAN
+    #[arg(short, long)]
+    phone_ip: Option<String>,
+    /// List phones found on LAN and exit
+    #[arg(long)]
+    discover: bool,
+    /// Receive tracking from a VMC protocol sender on this UDP port
+    #[arg(long)]
+    vmc_port: Option<u16>,
+    /// Name of custom value sent by inputs (like VMC blend shapes) used in transform config, can be repeated
+    #[arg(long)]
+    custom_variable: Vec<String>,
+    /// Receive tracking from Live Link Face app on this UDP port
+    #[arg(long)]
+    llf_port: Option<u16>,
+    /// Replay Live Link Face CSV take
+    #[arg(long)]
+    llf_csv: Option<String>,
+    /// Loop Live Link Face CSV take
+    #[arg(long, requires = "llf_csv")]
+    llf_loop: bool,
+    /// Receive frames relayed by another rusty-bridge on this UDP port
+    #[arg(long)]
+    relay_port: Option<u16>,
+    /// Relay frames in phone format to this UDP endpoint (`host:port`), can be repeated
+    #[arg(long)]
+    relay: Vec<String>,
+    /// Path to json file with transformation config applied to relayed frames
+    #[arg(long)]
+    relay_transform: Option<String>,
+    /// Pre-shared key to encrypt relayed frames, for both sending and receiving
+    #[arg(long)]
+    relay_key: Option<String>,
+    /// Act as phone for VTubeStudio on UDP port 21412
+    #[arg(long)]
+    emulate: bool,
+    /// Path to json file with transformation config applied to frames sent as phone
+    #[arg(long, requires = "emulate")]
+    emulate_transform: Option<String>,
+    /// Host of VTubeStudio, IP or hostname (when there are no sinks in config)
+    #[arg(long, default_value = "localhost")]
+    vts_host: String,
+    /// Port of VTubeStudio plugin API, discovered if not set (when there are no sinks in config)
+    #[arg(long)]
+    vts_port: Option<u16>,
+    /// Instance id or window title of discovered VTubeStudio (when there are no sinks in config)
+    #[arg(long, conflicts_with = "vts_port")]
+    vts_instance: Option<String>,
+    /// Max params per second sent to VTubeStudio (when there are no sinks in config)
+    #[arg(long)]
+    vts_rate: Option<f64>,
+    /// Send only params that changed at least by this value, all params twice a second (when there are no sinks in config)
+    #[arg(long)]
+    vts_min_change: Option<f64>,
+    /// Also write calculated params to JSON lines file
+    #[arg(long, requires = "transform_cfg")]
+    sink_file: Option<String>,
+    /// Also send calculated params as OSC to this UDP endpoint (`host:port`)
+    #[arg(long, requires = "transform_cfg")]
+    sink_osc: Option<String>,
+    /// Also send calculated params with VMC protocol to this UDP endpoint (`host:port`)
+    #[arg(long, requires = "transform_cfg")]
+    sink_vmc: Option<String>,
+    /// Also send calculated params as VRChat avatar parameters (to 127.0.0.1:9000 if endpoint is not set)
+    #[arg(long, requires = "transform_cfg", num_args = 0..=1, default_missing_value = DEFAULT_VRCHAT_TARGET)]
+    sink_vrchat: Option<String>,
+    /// Also print calculated params to log once per second
+    #[arg(long, requires = "transform_cfg")]
+    sink_debug: bool,
+    // Flattened last, so its help heading doesn't cover args after it
+    #[command(flatten)]
+    phone: PhoneSettings,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List VTubeStudio instances broadcasting on LAN and exit
+    ListInstances,
 }
 
 fn main() {
@@ -36,15 +128,278 @@ fn main() {
     let raw_log_config = serde_yaml::from_str(log_config).unwrap();
     log4rs::init_raw_config(raw_log_config).unwrap();
 
-    let (sender, receiver): (Sender<TrackingResponce>, Receiver<TrackingResponce>) =
-        mpsc::channel();
+    if args.discover {
+        println!("Searching for phones...");
+        match VtsPhone::discover(&args.phone, Duration::from_secs(3)) {
+            Ok(phones) if phones.is_empty() => println!("No phones found"),
+            Ok(phones) => {
+                for phone in phones {
+                    println!("{}", phone);
+                }
+            }
+            Err(e) => error!("Unable to search for phones: {}", e),
+        }
+        return;
+    }
+
+    if let Some(Command::ListInstances) = args.command {
+        println!("Searching for VTubeStudio instances...");
+        match VtsPc::discover(Duration::from_secs(3)) {
+            Ok(instances) if instances.is_empty() => println!("No VTubeStudio instances found"),
+            Ok(instances) => {
+                for instance in instances {
+                    println!("{}", instance);
+                }
+            }
+            Err(e) => error!("Unable to search for VTubeStudio instances: {}", e),
+        }
+        return;
+    }
+
+    let mut bridge_cfg = match args.config {
+        Some(path) => match BridgeCfg::load(&path) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                error!("Unable to load config: {}", e);
+                return;
+            }
+        },
+        None => BridgeCfg::default(),
+    };
+
+    if let Some(ip) = args.phone_ip {
+        bridge_cfg.inputs.push(
+            SourceCfg::Phone {
+                ip,
+                settings: args.phone,
+            }
+            .into(),
+        );
+    }
+    bridge_cfg.custom_variables.extend(args.custom_variable);
+    if let Some(port) = args.vmc_port {
+        bridge_cfg.inputs.push(SourceCfg::Vmc { port }.into());
+    }
+    if let Some(port) = args.llf_port {
+        bridge_cfg
+            .inputs
+            .push(SourceCfg::LiveLinkFace { port }.into());
+    }
+    if let Some(path) = args.llf_csv {
+        bridge_cfg.inputs.push(
+            SourceCfg::LiveLinkFaceCsv {
+                path,
+                looping: args.llf_loop,
+            }
+            .into(),
+        );
+    }
+
+    if let Some(port) = args.relay_port {
+        bridge_cfg.inputs.push(
+            SourceCfg::Relay {
+                port,
+                key: args.relay_key.clone(),
+            }
+            .into(),
+        );
+    }
+    bridge_cfg.relay.targets.extend(args.relay);
+    if args.relay_transform.is_some() {
+        bridge_cfg.relay.transform = args.relay_transform;
+    }
+    if args.relay_key.is_some() {
+        bridge_cfg.relay.key = args.relay_key;
+    }
+    if args.emulate {
+        let emulator = bridge_cfg.emulator.get_or_insert_with(Default::default);
+        if args.emulate_transform.is_some() {
+            emulator.transform = args.emulate_transform;
+        }
+    }
+
+    if bridge_cfg.sinks.is_empty() {
+        let vts = VtsCfg {
+            host: args.vts_host,
+            port: args.vts_port,
+            instance: args.vts_instance,
+            min_change: args.vts_min_change,
+            ..Default::default()
+        };
+        bridge_cfg.sinks.push(SinkCfg {
+            rate: args.vts_rate,
+            ..OutputCfg::Vts(vts).into()
+        });
+    }
+    if let Some(path) = args.sink_file {
+        bridge_cfg.sinks.push(OutputCfg::File { path }.into());
+    }
+    if let Some(target) = args.sink_osc {
+        let prefix = DEFAULT_OSC_PREFIX.to_string();
+        bridge_cfg
+            .sinks
+            .push(OutputCfg::Osc { target, prefix }.into());
+    }
+    if let Some(target) = args.sink_vmc {
+        let head = Default::default();
+        bridge_cfg
+            .sinks
+            .push(OutputCfg::Vmc { target, head }.into());
+    }
+    if let Some(target) = args.sink_vrchat {
+        let types = Default::default();
+        bridge_cfg
+            .sinks
+            .push(OutputCfg::VrChat { target, types }.into());
+    }
+    if args.sink_debug {
+        bridge_cfg.sinks.push(OutputCfg::Debug.into());
+    }
+
+    if bridge_cfg.inputs.is_empty() {
+        error!("No inputs: set phone ip or add inputs to config");
+        return;
+    }
+
+    let relay = bridge_cfg.relay.clone();
+    let emulator = bridge_cfg.emulator.clone();
+    if args.transform_cfg.is_none() && relay.targets.is_empty() && emulator.is_none() {
+        error!("No outputs: set transform config, relay targets or phone emulation");
+        return;
+    }
+
+    let variables = bridge_cfg.variables();
+    let profiles = bridge_cfg.hotkeys.profiles.values();
+    let emulator_transform = emulator.as_ref().and_then(|e| e.transform.as_ref());
+    let sink_transforms = bridge_cfg.sinks.iter().filter_map(|s| s.transform.as_ref());
+    let transforms = args
+        .transform_cfg
+        .iter()
+        .chain(&relay.transform)
+        .chain(emulator_transform)
+        .chain(sink_transforms);
+    for path in transforms.chain(profiles) {
+        if let Err(e) = VtsPc::check_transform_cfg(path, &variables) {
+            error!("Invalid transform config: {}", e);
+            return;
+        }
+    }
+
+    let mut sinks = Vec::new();
+    let mut vts_monitors = Vec::new();
+    if args.transform_cfg.is_some() {
+        for cfg in bridge_cfg.sinks.drain(..) {
+            match cfg.output.build(&mut vts_monitors) {
+                Ok(sink) => sinks.push((sink, cfg.transform, cfg.rate, cfg.on_error)),
+                Err(e) => {
+                    error!("Unable to create sink: {}", e);
+                    return;
+                }
+            }
+        }
+    }
 
-    let pctr_handler = thread::spawn(move || {
-        VtsPc::run(receiver, args.transform_cfg, active_flag);
-    });
+    let relay_transform = match relay.transform.as_deref().map(Transform::load).transpose() {
+        Ok(transform) => transform,
+        Err(e) => {
+            error!("Unable to load relay transform config: {}", e);
+            return;
+        }
+    };
+    let emulator_path = emulator.as_ref().and_then(|e| e.transform.as_deref());
+    let emulator_transform = match emulator_path.map(Transform::load).transpose() {
+        Ok(transform) => transform,
+        Err(e) => {
+            error!("Unable to load emulator transform config: {}", e);
+            return;
+        }
+    };
 
-    let phonetr_handler = thread::spawn(move || VtsPhone::run(args.phone_ip, sender, active_flag2));
+    let inputs = bridge_cfg.spawn_inputs(&active_flag);
+
+    for (name, monitor) in inputs.phones {
+        let events = monitor.subscribe();
+        thread::spawn(move || log_phone_status(name, events));
+    }
+    for (name, monitor) in vts_monitors {
+        let events = monitor.subscribe();
+        thread::spawn(move || log_vts_status(name, events));
+    }
+
+    let mut handlers = inputs.handlers;
+    let mut merger = inputs.merger;
+    // Outputs other than VTubeStudio plugin API get frames on their own threads
+    let mut senders = Vec::new();
+
+    if !relay.targets.is_empty() {
+        let (sender, receiver) = slot();
+        senders.push(sender);
+        let flag = Arc::clone(&active_flag);
+        handlers.push(thread::spawn(move || {
+            Relay::run(relay, relay_transform, receiver, flag)
+        }));
+    }
+
+    if let Some(emulator) = emulator {
+        let (sender, receiver) = slot();
+        senders.push(sender);
+        let flag = Arc::clone(&active_flag);
+        handlers.push(thread::spawn(move || {
+            PhoneEmulator::run(emulator.port, emulator_transform, receiver, flag)
+        }));
+    }
+
+    if !senders.is_empty() {
+        let (sender, receiver) = slot();
+        senders.push(sender);
+        let flag = Arc::clone(&active_flag);
+        handlers.push(thread::spawn(move || merger.fan_out(senders, flag)));
+        merger = Merger::single(receiver);
+    }
+
+    if let Some(transform_cfg) = args.transform_cfg {
+        let mut senders = Vec::new();
+        for (sink, transform, rate, on_error) in sinks {
+            let (sender, receiver) = mpsc::channel();
+            senders.push((transform, sender));
+            let flag = Arc::clone(&active_flag);
+            handlers.push(thread::spawn(move || {
+                run_sink(sink, rate, on_error, receiver, flag)
+            }));
+        }
+
+        let hotkeys = bridge_cfg.hotkeys;
+        handlers.push(thread::spawn(move || {
+            Pipeline::run(merger, transform_cfg, hotkeys, senders, active_flag2);
+        }));
+    }
+
+    for handler in handlers {
+        let _ = handler.join();
+    }
+}
+
+/// State changes are logged by monitor, this tells how to get out of states waiting for user
+fn log_vts_status(name: String, events: Receiver<VtsEvent>) {
+    for VtsEvent::StateChanged { to, .. } in events {
+        if to == VtsState::Denied {
+            warn!(
+                "{}: plugin is requested again after VTubeStudio reconnects or profile is switched",
+                name
+            );
+        }
+    }
+}
 
-    let _ = pctr_handler.join();
-    let _ = phonetr_handler.join();
+/// Print phone statistics every 10 seconds while it streams
+fn log_phone_status(name: String, events: Receiver<PhoneEvent>) {
+    let mut next_time = Instant::now();
+    for event in events {
+        if let PhoneEvent::Stats(status) = event {
+            if status.state == PhoneState::Streaming && next_time <= Instant::now() {
+                next_time = Instant::now() + Duration::from_secs(10);
+                info!("Phone {}: {}", name, status);
+            }
+        }
+    }
 }
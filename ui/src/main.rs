@@ -6,7 +6,6 @@ use std::{
     env, fs,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::{self, Receiver, Sender},
         Arc,
     },
     thread::{self},
@@ -15,8 +14,12 @@ use std::{
 use nwd::NwgUi;
 use nwg::NativeUi;
 use rusty_bridge_lib::{
+    health::{PhoneMonitor, VtsMonitor, VtsState},
+    hotkeys::HotkeysCfg,
+    merge::Merger,
+    slot::slot,
     vtspc::VtsPc,
-    vtsphone::{TrackingResponce, VtsPhone},
+    vtsphone::{PhoneSettings, TrackingResponce, VtsPhone},
 };
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -28,7 +31,7 @@ pub struct UiCfg {
 
 #[derive(Default, NwgUi)]
 pub struct App {
-    #[nwg_control(size: (300, 140), position: (300, 300), title: "Rusty Bridge", flags: "WINDOW|VISIBLE")]
+    #[nwg_control(size: (300, 160), position: (300, 300), title: "Rusty Bridge", flags: "WINDOW|VISIBLE")]
     #[nwg_events( OnWindowClose: [App::close], OnInit: [App::init] )]
     window: nwg::Window,
 
@@ -43,7 +46,7 @@ pub struct App {
     #[nwg_events( OnButtonClick: [App::open_file] )]
     file_button: nwg::Button,
 
-    #[nwg_control(size: (280, 25), position: (10, 52), placeholder_text: Some("(0.0.0.0) IPhone Ip"))]
+    #[nwg_control(size: (280, 25), position: (10, 52), placeholder_text: Some("IPhone Ip, hostname or auto"))]
     #[nwg_events( OnTextInput: [App::save] )]
     phone_ip: nwg::TextInput,
 
@@ -54,7 +57,14 @@ pub struct App {
     #[nwg_resource(size: 14)]
     label_font: nwg::Font,
 
-    #[nwg_control(text: "https://github.com/ovROG/rusty-bridge", position: (10, 120), size: (240, 15), font: Some(&data.label_font))]
+    #[nwg_control(text: "Disconnected", position: (10, 122), size: (280, 15), font: Some(&data.label_font))]
+    status: nwg::Label,
+
+    #[nwg_control(parent: window)]
+    #[nwg_events( OnNotice: [App::update_status] )]
+    status_notice: nwg::Notice,
+
+    #[nwg_control(text: "https://github.com/ovROG/rusty-bridge", position: (10, 140), size: (240, 15), font: Some(&data.label_font))]
     credits: nwg::Label,
 
     #[nwg_resource( action: FileDialogAction::Open, title: "Select Transfom File")]
@@ -76,12 +86,30 @@ pub struct App {
     tray_item3: nwg::MenuItem,
 
     active: Arc<AtomicBool>,
+
+    phone_monitor: PhoneMonitor,
+    vts_monitor: VtsMonitor,
 }
 
 impl App {
     fn init(&self) {
         let em = &self.embed;
         self.window.set_icon(em.icon_str("APP_ICON", None).as_ref());
+
+        let notice = self.status_notice.sender();
+        let events = self.phone_monitor.subscribe();
+        thread::spawn(move || {
+            for _ in events {
+                notice.notice();
+            }
+        });
+        let notice = self.status_notice.sender();
+        let events = self.vts_monitor.subscribe();
+        thread::spawn(move || {
+            for _ in events {
+                notice.notice();
+            }
+        });
         if let Ok(last_config) = fs::read_to_string("ui-cfg.json") {
             let cfg = serde_json::from_str::<UiCfg>(&last_config).unwrap();
 
@@ -107,21 +135,35 @@ impl App {
 
     fn connect(&self) {
         if !self.active.load(Ordering::Relaxed) {
-            self.active.store(true, Ordering::Relaxed);
             let path = self.transform_file_path.text().clone();
             let ip = self.phone_ip.text().clone();
 
-            let (sender, receiver): (Sender<TrackingResponce>, Receiver<TrackingResponce>) =
-                mpsc::channel();
+            if let Err(e) = VtsPc::check_transform_cfg(&path, &[]) {
+                nwg::modal_error_message(&self.window, "Invalid transform config", &e);
+                return;
+            }
+            self.active.store(true, Ordering::Relaxed);
+
+            let (sender, receiver) = slot::<TrackingResponce>();
 
             let flag_pc = Arc::clone(&self.active);
             let flag_ph = Arc::clone(&self.active);
 
+            let vts_monitor = self.vts_monitor.clone();
             let _ = thread::spawn(move || {
-                VtsPc::run(receiver, path, flag_pc);
+                VtsPc::run(
+                    Merger::single(receiver),
+                    path,
+                    HotkeysCfg::default(),
+                    vts_monitor,
+                    flag_pc,
+                );
             });
 
-            let _ = thread::spawn(move || VtsPhone::run(ip, sender, flag_ph));
+            let monitor = self.phone_monitor.clone();
+            let _ = thread::spawn(move || {
+                VtsPhone::run(ip, PhoneSettings::default(), sender, flag_ph, monitor)
+            });
 
             self.transform_file_path.set_readonly(true);
             self.phone_ip.set_readonly(true);
@@ -134,12 +176,25 @@ impl App {
             self.phone_ip.set_readonly(false);
             self.file_button.set_enabled(true);
             self.connect_button.set_text("Connect");
+            self.status.set_text("Disconnected");
         }
 
         // let _ = pctr_handler.join();
         // let _ = phonetr_handler.join();
     }
 
+    /// VTubeStudio state until plugin is authenticated, then phone status
+    fn update_status(&self) {
+        if self.active.load(Ordering::Relaxed) {
+            match self.vts_monitor.state() {
+                VtsState::Authenticated => self
+                    .status
+                    .set_text(&self.phone_monitor.status().to_string()),
+                state => self.status.set_text(&format!("VTubeStudio: {}", state)),
+            }
+        }
+    }
+
     fn open_file(&self) {
         if let Ok(d) = env::current_dir() {
             if let Some(d) = d.to_str() {
@@ -0,0 +1,46 @@
+use std::sync::{atomic::AtomicBool, Arc};
+
+use clap::Parser;
+use log::error;
+use rusty_bridge_lib::mockphone::{MockFrames, MockPhone};
+
+/// Acts as VTubeStudio iPhone app for tests and demos
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// UDP port for tracking requests
+    #[arg(short, long, default_value_t = 21412)]
+    port: u16,
+    /// Frames per second of synthetic tracking
+    #[arg(long, default_value_t = 60)]
+    fps: u32,
+    /// Stream recorded frames (JSON lines from `record` hotkey action) instead of synthetic ones
+    #[arg(short, long)]
+    recording: Option<String>,
+    /// Loop recording
+    #[arg(long, requires = "recording")]
+    looping: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let log_config = include_str!("../../../configs/log_cfg.yml");
+    let raw_log_config = serde_yaml::from_str(log_config).unwrap();
+    log4rs::init_raw_config(raw_log_config).unwrap();
+
+    let frames = match args.recording {
+        Some(path) => match MockFrames::read_recording(&path, args.looping) {
+            Ok(frames) => frames,
+            Err(e) => {
+                error!("Unable to read recording: {}", e);
+                return;
+            }
+        },
+        None => MockFrames::Synthetic { fps: args.fps },
+    };
+
+    if let Err(e) = MockPhone::run(args.port, frames, Arc::new(AtomicBool::new(true))) {
+        error!("Mock phone failed: {}", e);
+    }
+}
@@ -0,0 +1,25 @@
+/// Next `len` bytes of big endian packet (OSC, Live Link Face)
+pub(crate) fn take<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    if *pos + len > buf.len() {
+        return Err("Unexpected end of packet".to_string());
+    }
+    let data = &buf[*pos..*pos + len];
+    *pos += len;
+    Ok(data)
+}
+
+pub(crate) fn read_u32(buf: &[u8], pos: &mut usize) -> Result<u32, String> {
+    let data = take(buf, pos, 4)?;
+    Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+}
+
+pub(crate) fn read_i32(buf: &[u8], pos: &mut usize) -> Result<i32, String> {
+    Ok(read_u32(buf, pos)? as i32)
+}
+
+pub(crate) fn read_i64(buf: &[u8], pos: &mut usize) -> Result<i64, String> {
+    let data = take(buf, pos, 8)?;
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(data);
+    Ok(i64::from_be_bytes(bytes))
+}
@@ -0,0 +1,252 @@
+use std::{
+    fs,
+    sync::{atomic::AtomicBool, Arc},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    health::PhoneMonitor,
+    hotkeys::HotkeysCfg,
+    jsoninput::{JsonInput, JsonInputCfg},
+    livelink::LiveLinkFace,
+    merge::{MergeCfg, Merger, Source},
+    phoneserver::EmulatorCfg,
+    relay::{Relay, RelayCfg},
+    sink::SinkCfg,
+    slot::{slot, SlotSender},
+    transform::HEAD_VARIABLES,
+    vmc::Vmc,
+    vtsphone::{unknown_name, BlendShape, PhoneSettings, TrackingResponce, VtsPhone},
+};
+
+/// Left eye cords, can be merged but are not set for transform config
+const EYE_VARIABLES: [&str; 3] = ["EyeLeftX", "EyeLeftY", "EyeLeftZ"];
+
+/// Bridge config, json file passed with `--config`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BridgeCfg {
+    #[serde(default)]
+    pub inputs: Vec<InputCfg>,
+    #[serde(default)]
+    pub merge: MergeCfg,
+    /// Names of custom values sent by inputs (like VMC blend shapes) used in transform config
+    #[serde(default)]
+    pub custom_variables: Vec<String>,
+    #[serde(default)]
+    pub hotkeys: HotkeysCfg,
+    #[serde(default)]
+    pub relay: RelayCfg,
+    /// Act as phone for VTubeStudio
+    pub emulator: Option<EmulatorCfg>,
+    /// Outputs for params calculated with transform config, VTubeStudio if empty
+    #[serde(default)]
+    pub sinks: Vec<SinkCfg>,
+}
+
+impl BridgeCfg {
+    pub fn load(path: &str) -> Result<BridgeCfg, String> {
+        let config = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("{}: {}", path, e)),
+        };
+        let cfg: BridgeCfg =
+            serde_json::from_str(&config).map_err(|e| format!("{}: {}", path, e))?;
+        cfg.check_merge_variables()
+            .and_then(|_| cfg.check_phones())
+            .and_then(|_| cfg.hotkeys.check())
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Ok(cfg)
+    }
+
+    /// Custom variable names in addition to head cords and ARKit blend shapes
+    pub fn variables(&self) -> Vec<String> {
+        let json_fields = self.inputs.iter().filter_map(|input| match &input.source {
+            SourceCfg::Json(cfg) => Some(cfg.fields.keys()),
+            _ => None,
+        });
+        json_fields
+            .flatten()
+            .chain(&self.custom_variables)
+            .filter(|name| !HEAD_VARIABLES.contains(&name.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    fn check_phones(&self) -> Result<(), String> {
+        for input in &self.inputs {
+            if let SourceCfg::Phone { settings, .. } = &input.source {
+                settings.check()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_merge_variables(&self) -> Result<(), String> {
+        let variables = self.variables();
+        let known = || {
+            HEAD_VARIABLES
+                .iter()
+                .chain(&EYE_VARIABLES)
+                .copied()
+                .chain(BlendShape::ALL.iter().map(|shape| shape.name()))
+                .chain(variables.iter().map(String::as_str))
+        };
+
+        let errors: Vec<String> = self
+            .merge
+            .variables
+            .keys()
+            .filter(|name| !known().any(|k| k == name.as_str()))
+            .map(|name| unknown_name("merge variable", name, known()))
+            .collect();
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(errors.join("\n"))
+    }
+
+    /// Start every input on its own thread
+    pub fn spawn_inputs(&self, active: &Arc<AtomicBool>) -> Inputs {
+        let mut inputs: Vec<&InputCfg> = self.inputs.iter().collect();
+        inputs.sort_by_key(|input| -input.priority);
+
+        let mut sources = Vec::new();
+        let mut handlers = Vec::new();
+        let mut phones = Vec::new();
+
+        for (i, input) in inputs.into_iter().enumerate() {
+            let (sender, receiver) = slot();
+            let name = input
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{}#{}", input.source.kind(), i));
+
+            let monitor = PhoneMonitor::default();
+            if let SourceCfg::Phone { .. } = input.source {
+                phones.push((name.clone(), monitor.clone()));
+            }
+
+            handlers.push(
+                input
+                    .source
+                    .clone()
+                    .spawn(sender, Arc::clone(active), monitor),
+            );
+            sources.push(Source {
+                name,
+                weight: input.weight,
+                receiver,
+            });
+        }
+
+        Inputs {
+            merger: Merger::new(self.merge.clone(), sources),
+            handlers,
+            phones,
+        }
+    }
+}
+
+/// Running inputs
+pub struct Inputs {
+    /// Combines frames of all inputs
+    pub merger: Merger,
+    pub handlers: Vec<JoinHandle<()>>,
+    /// Status of phone inputs by name
+    pub phones: Vec<(String, PhoneMonitor)>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InputCfg {
+    /// Name in logs, kind of input if not set
+    pub name: Option<String>,
+    /// Higher is preferred, inputs with same priority keep config order
+    #[serde(default)]
+    pub priority: i32,
+    /// Weight for `average` merge policy
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    #[serde(flatten)]
+    pub source: SourceCfg,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+impl From<SourceCfg> for InputCfg {
+    fn from(source: SourceCfg) -> Self {
+        InputCfg {
+            name: None,
+            priority: 0,
+            weight: default_weight(),
+            source,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum SourceCfg {
+    Phone {
+        ip: String,
+        #[serde(flatten)]
+        settings: PhoneSettings,
+    },
+    Vmc {
+        port: u16,
+    },
+    LiveLinkFace {
+        port: u16,
+    },
+    #[serde(rename_all = "camelCase")]
+    LiveLinkFaceCsv {
+        path: String,
+        #[serde(default)]
+        looping: bool,
+    },
+    Json(JsonInputCfg),
+    /// Frames relayed by another rusty-bridge
+    Relay {
+        port: u16,
+        /// Pre-shared key of encrypted relay
+        key: Option<String>,
+    },
+}
+
+impl SourceCfg {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SourceCfg::Phone { .. } => "phone",
+            SourceCfg::Vmc { .. } => "vmc",
+            SourceCfg::LiveLinkFace { .. } => "liveLinkFace",
+            SourceCfg::LiveLinkFaceCsv { .. } => "liveLinkFaceCsv",
+            SourceCfg::Json(_) => "json",
+            SourceCfg::Relay { .. } => "relay",
+        }
+    }
+
+    /// Start input on its own thread, monitor is used only by phone input
+    pub fn spawn(
+        self,
+        sender: SlotSender<TrackingResponce>,
+        active: Arc<AtomicBool>,
+        monitor: PhoneMonitor,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || match self {
+            SourceCfg::Phone { ip, settings } => {
+                VtsPhone::run(ip, settings, sender, active, monitor)
+            }
+            SourceCfg::Vmc { port } => Vmc::run(port, sender, active),
+            SourceCfg::LiveLinkFace { port } => LiveLinkFace::run(port, sender, active),
+            SourceCfg::LiveLinkFaceCsv { path, looping } => {
+                LiveLinkFace::replay(path, looping, sender, active)
+            }
+            SourceCfg::Json(cfg) => JsonInput::run(cfg, sender, active),
+            SourceCfg::Relay { port, key } => Relay::listen(port, key, sender, active),
+        })
+    }
+}
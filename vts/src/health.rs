@@ -0,0 +1,303 @@
+use std::{
+    fmt,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum PhoneState {
+    /// No tracking data received yet
+    #[default]
+    Connecting,
+    Streaming,
+    /// No tracking data for a read timeout
+    Stalled,
+    /// No tracking data for a lost timeout
+    Lost,
+}
+
+impl fmt::Display for PhoneState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PhoneState::Connecting => "Connecting",
+            PhoneState::Streaming => "Streaming",
+            PhoneState::Stalled => "Stalled",
+            PhoneState::Lost => "Lost",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PhoneStatus {
+    pub state: PhoneState,
+    pub packets: u64,
+    /// Packets per second over the last second
+    pub packet_rate: f64,
+    /// Smoothed inter-arrival jitter in milliseconds
+    pub jitter_ms: f64,
+    pub deserialize_errors: u64,
+    pub out_of_order: u64,
+    pub last_packet: Option<Instant>,
+}
+
+impl fmt::Display for PhoneStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:.1} pkt/s, jitter {:.1} ms, {} bad, {} out of order",
+            self.state,
+            self.packet_rate,
+            self.jitter_ms,
+            self.deserialize_errors,
+            self.out_of_order
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PhoneEvent {
+    StateChanged {
+        from: PhoneState,
+        to: PhoneState,
+    },
+    DeserializeFailed(String),
+    OutOfOrder {
+        timestamp: u64,
+        last: u64,
+    },
+    /// Sent every second while phone input runs
+    Stats(PhoneStatus),
+}
+
+/// Shared status of a phone input, cheap to clone
+#[derive(Clone, Default)]
+pub struct PhoneMonitor {
+    inner: Arc<Mutex<MonitorInner>>,
+}
+
+#[derive(Default)]
+struct MonitorInner {
+    status: PhoneStatus,
+    subscribers: Vec<Sender<PhoneEvent>>,
+}
+
+impl PhoneMonitor {
+    pub fn status(&self) -> PhoneStatus {
+        self.inner.lock().unwrap().status.clone()
+    }
+
+    pub fn subscribe(&self) -> Receiver<PhoneEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.inner.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    fn emit(inner: &mut MonitorInner, event: PhoneEvent) {
+        inner
+            .subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+/// Statistics collected by the phone input thread, published to `PhoneMonitor`
+pub(crate) struct PhoneTracker {
+    name: String,
+    monitor: PhoneMonitor,
+    stall_timeout: Duration,
+    lost_timeout: Duration,
+    started: Instant,
+    last_interval: Option<Duration>,
+    last_timestamp: Option<u64>,
+    window_start: Instant,
+    window_packets: u64,
+}
+
+impl PhoneTracker {
+    pub fn new(
+        name: String,
+        monitor: PhoneMonitor,
+        stall_timeout: Duration,
+        lost_timeout: Duration,
+    ) -> Self {
+        monitor.inner.lock().unwrap().status = PhoneStatus::default();
+        PhoneTracker {
+            name,
+            monitor,
+            stall_timeout,
+            lost_timeout,
+            started: Instant::now(),
+            last_interval: None,
+            last_timestamp: None,
+            window_start: Instant::now(),
+            window_packets: 0,
+        }
+    }
+
+    pub fn state(&self) -> PhoneState {
+        self.monitor.inner.lock().unwrap().status.state
+    }
+
+    /// Returns false if timestamp is not newer than the last one and frame should be dropped
+    pub fn packet(&mut self, timestamp: u64) -> bool {
+        let now = Instant::now();
+        let mut inner = self.monitor.inner.lock().unwrap();
+
+        // Gaps after stall are not jitter
+        let streaming = inner.status.state == PhoneState::Streaming;
+        match inner.status.last_packet {
+            Some(last) if streaming => {
+                let interval = now - last;
+                if let Some(prev) = self.last_interval {
+                    let d = interval.as_secs_f64() - prev.as_secs_f64();
+                    inner.status.jitter_ms += (d.abs() * 1000.0 - inner.status.jitter_ms) / 16.0;
+                }
+                self.last_interval = Some(interval);
+            }
+            _ => self.last_interval = None,
+        }
+
+        inner.status.packets += 1;
+        inner.status.last_packet = Some(now);
+        self.window_packets += 1;
+        PhoneTracker::set_state(&self.name, &mut inner, PhoneState::Streaming);
+
+        let in_order = self.last_timestamp.is_none_or(|last| timestamp > last);
+        if in_order {
+            self.last_timestamp = Some(timestamp);
+        } else {
+            inner.status.out_of_order += 1;
+            let last = self.last_timestamp.unwrap_or_default();
+            PhoneMonitor::emit(&mut inner, PhoneEvent::OutOfOrder { timestamp, last });
+        }
+        in_order
+    }
+
+    pub fn deserialize_failed(&mut self, error: String) {
+        let mut inner = self.monitor.inner.lock().unwrap();
+        inner.status.deserialize_errors += 1;
+        PhoneMonitor::emit(&mut inner, PhoneEvent::DeserializeFailed(error));
+    }
+
+    /// Update state and rate, called after every receive attempt
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        let mut inner = self.monitor.inner.lock().unwrap();
+
+        let silent = now - inner.status.last_packet.unwrap_or(self.started);
+        let state = match inner.status.state {
+            _ if silent >= self.lost_timeout => PhoneState::Lost,
+            PhoneState::Streaming if silent >= self.stall_timeout => PhoneState::Stalled,
+            state => state,
+        };
+        if state == PhoneState::Lost {
+            // Phone app may restart with a different clock
+            self.last_timestamp = None;
+        }
+        PhoneTracker::set_state(&self.name, &mut inner, state);
+
+        let window = now - self.window_start;
+        if window >= Duration::from_secs(1) {
+            inner.status.packet_rate = self.window_packets as f64 / window.as_secs_f64();
+            self.window_start = now;
+            self.window_packets = 0;
+            let stats = PhoneEvent::Stats(inner.status.clone());
+            PhoneMonitor::emit(&mut inner, stats);
+        }
+    }
+
+    fn set_state(name: &str, inner: &mut MonitorInner, state: PhoneState) {
+        let from = inner.status.state;
+        if from == state {
+            return;
+        }
+        match state {
+            PhoneState::Stalled | PhoneState::Lost => warn!("Phone {}: {}", name, state),
+            _ => info!("Phone {}: {}", name, state),
+        }
+        inner.status.state = state;
+        PhoneMonitor::emit(inner, PhoneEvent::StateChanged { from, to: state });
+    }
+}
+
+/// Authentication state of VTubeStudio plugin API connection
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum VtsState {
+    #[default]
+    Disconnected,
+    /// Connected, authentication is checked
+    Connected,
+    /// Token is requested, popup is shown in VTubeStudio
+    WaitingForUser,
+    /// User denied the plugin, token is requested again later
+    Denied,
+    Authenticating,
+    Authenticated,
+}
+
+impl fmt::Display for VtsState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            VtsState::Disconnected => "Disconnected",
+            VtsState::Connected => "Connected",
+            VtsState::WaitingForUser => "Waiting for user to accept plugin in VTubeStudio",
+            VtsState::Denied => "Plugin denied in VTubeStudio",
+            VtsState::Authenticating => "Authenticating",
+            VtsState::Authenticated => "Authenticated",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum VtsEvent {
+    StateChanged { from: VtsState, to: VtsState },
+}
+
+/// Shared state of a VTubeStudio sink, cheap to clone
+#[derive(Clone, Default)]
+pub struct VtsMonitor {
+    inner: Arc<Mutex<VtsMonitorInner>>,
+}
+
+#[derive(Default)]
+struct VtsMonitorInner {
+    state: VtsState,
+    subscribers: Vec<Sender<VtsEvent>>,
+}
+
+impl VtsMonitor {
+    pub fn state(&self) -> VtsState {
+        self.inner.lock().unwrap().state
+    }
+
+    pub fn subscribe(&self) -> Receiver<VtsEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.inner.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    pub(crate) fn set_state(&self, name: &str, state: VtsState) {
+        let mut inner = self.inner.lock().unwrap();
+        let from = inner.state;
+        if from == state {
+            return;
+        }
+        match state {
+            VtsState::Disconnected | VtsState::Denied => warn!("{}: {}", name, state),
+            _ => info!("{}: {}", name, state),
+        }
+        inner.state = state;
+        let event = VtsEvent::StateChanged { from, to: state };
+        inner
+            .subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+
+use crate::vtsphone::{Cords, TrackingResponce};
+
+/// Action for a phone hotkey, dispatched when hotkey id changes to it
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum HotkeyAction {
+    /// Switch to transform config from `profiles`
+    Profile { name: String },
+    /// Use average head position and rotation over next second as zero
+    Calibrate,
+    /// Trigger VTube Studio hotkey by id or name
+    VtsHotkey { id: String },
+    /// Stop or resume sending params of a group from `groups`
+    ToggleGroup { name: String },
+    /// Start or stop recording raw frames to JSON lines file
+    Record { path: String },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HotkeysCfg {
+    /// Phone hotkey id to action
+    #[serde(default)]
+    pub actions: HashMap<i16, HotkeyAction>,
+    /// Profile name to transform config path
+    #[serde(default)]
+    pub profiles: HashMap<String, String>,
+    /// Group name to param names
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+}
+
+impl HotkeysCfg {
+    /// Names used by actions that are missing in `profiles` or `groups`
+    pub fn check(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (id, action) in &self.actions {
+            match action {
+                HotkeyAction::Profile { name } if !self.profiles.contains_key(name) => {
+                    errors.push(format!("hotkey {}: unknown profile `{}`", id, name))
+                }
+                HotkeyAction::ToggleGroup { name } if !self.groups.contains_key(name) => {
+                    errors.push(format!("hotkey {}: unknown group `{}`", id, name))
+                }
+                _ => {}
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(errors.join("\n"))
+    }
+}
+
+const CALIBRATION_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Calibration {
+    rotation: Cords,
+    position: Cords,
+    /// Start and collected frames while calibrating
+    collecting: Option<(Instant, Vec<(Cords, Cords)>)>,
+}
+
+/// State changed by hotkeys, kept between reconnects to VTube Studio
+pub(crate) struct Hotkeys {
+    cfg: HotkeysCfg,
+    last: i16,
+    disabled: HashSet<String>,
+    calibration: Calibration,
+    recording: Option<BufWriter<File>>,
+}
+
+impl Hotkeys {
+    pub fn new(cfg: HotkeysCfg) -> Self {
+        Hotkeys {
+            cfg,
+            last: -1,
+            disabled: HashSet::new(),
+            calibration: Calibration::default(),
+            recording: None,
+        }
+    }
+
+    /// Action of hotkey if it changed since last frame
+    pub fn pressed(&mut self, hotkey: i16) -> Option<HotkeyAction> {
+        if hotkey == self.last {
+            return None;
+        }
+        self.last = hotkey;
+        let action = self.cfg.actions.get(&hotkey).cloned();
+        if let Some(action) = &action {
+            info!("Hotkey {}: {:?}", hotkey, action);
+        }
+        action
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&String> {
+        self.cfg.profiles.get(name)
+    }
+
+    pub fn is_enabled(&self, param: &str) -> bool {
+        !self.disabled.contains(param)
+    }
+
+    pub fn toggle_group(&mut self, name: &str) {
+        let params = match self.cfg.groups.get(name) {
+            Some(params) => params,
+            None => {
+                warn!("Unknown group: {}", name);
+                return;
+            }
+        };
+
+        // Group is enabled again if any of its params is disabled
+        if params.iter().any(|param| self.disabled.contains(param)) {
+            params.iter().for_each(|param| {
+                self.disabled.remove(param);
+            });
+            info!("Group {} enabled", name);
+        } else {
+            self.disabled.extend(params.iter().cloned());
+            info!("Group {} disabled", name);
+        }
+    }
+
+    pub fn calibrate(&mut self) {
+        info!("Calibrating, keep head in neutral position");
+        self.calibration.collecting = Some((Instant::now(), Vec::new()));
+    }
+
+    pub fn toggle_recording(&mut self, path: &str) {
+        if let Some(mut file) = self.recording.take() {
+            let _ = file
+                .flush()
+                .map_err(|e| error!("Unable to save recording: {}", e));
+            info!("Recording stopped");
+            return;
+        }
+
+        match File::create(path) {
+            Ok(file) => {
+                info!("Recording to {}", path);
+                self.recording = Some(BufWriter::new(file));
+            }
+            Err(e) => error!("Unable to record to {}: {}", path, e),
+        }
+    }
+
+    /// Record raw frame, then apply calibration
+    pub fn frame(&mut self, frame: &mut TrackingResponce) {
+        if let Some(file) = &mut self.recording {
+            let written = serde_json::to_writer(&mut *file, frame)
+                .map_err(|e| e.to_string())
+                .and_then(|_| file.write_all(b"\n").map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                error!("Unable to record frame: {}", e);
+                self.recording = None;
+            }
+        }
+
+        let calibration = &mut self.calibration;
+        if let Some((started, frames)) = &mut calibration.collecting {
+            if frame.face_found {
+                frames.push((frame.rotation.clone(), frame.position.clone()));
+            }
+            if started.elapsed() >= CALIBRATION_TIME {
+                if frames.is_empty() {
+                    warn!("Calibration failed, no face found");
+                } else {
+                    let n = frames.len() as f64;
+                    let avg = |get: fn(&(Cords, Cords)) -> &Cords| Cords {
+                        x: frames.iter().map(|f| get(f).x).sum::<f64>() / n,
+                        y: frames.iter().map(|f| get(f).y).sum::<f64>() / n,
+                        z: frames.iter().map(|f| get(f).z).sum::<f64>() / n,
+                    };
+                    calibration.rotation = avg(|f| &f.0);
+                    calibration.position = avg(|f| &f.1);
+                    info!("Calibrated");
+                }
+                calibration.collecting = None;
+            }
+        }
+
+        let sub = |cords: &mut Cords, offset: &Cords| {
+            cords.x -= offset.x;
+            cords.y -= offset.y;
+            cords.z -= offset.z;
+        };
+        sub(&mut frame.rotation, &calibration.rotation);
+        sub(&mut frame.position, &calibration.position);
+    }
+}
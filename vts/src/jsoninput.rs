@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use log::{error, info, warn};
+use serde_json::Value;
+
+use crate::{
+    slot::SlotSender,
+    vtsphone::{now_millis, BlendShapes, TrackingResponce},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum JsonTransport {
+    Udp,
+    Websocket,
+}
+
+/// Input for custom trackers sending JSON objects
+///
+/// `fields` maps expression variable names to JSON pointers (`/head/rot/0`),
+/// `HeadRotX/Y/Z` and `HeadPosX/Y/Z` go to head cords, any other name is a blend shape
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonInputCfg {
+    pub transport: JsonTransport,
+    pub port: u16,
+    pub fields: HashMap<String, String>,
+    /// Pointer to bool or number, face is always found if not set
+    pub face_found: Option<String>,
+    /// Pointer to timestamp in milliseconds, local time if not set
+    pub timestamp: Option<String>,
+}
+
+pub struct JsonInput;
+
+impl JsonInput {
+    pub fn run(cfg: JsonInputCfg, sender: SlotSender<TrackingResponce>, active: Arc<AtomicBool>) {
+        match cfg.transport {
+            JsonTransport::Udp => JsonInput::run_udp(&cfg, &sender, &active),
+            JsonTransport::Websocket => JsonInput::run_websocket(&cfg, &sender, &active),
+        }
+    }
+
+    fn run_udp(cfg: &JsonInputCfg, sender: &SlotSender<TrackingResponce>, active: &AtomicBool) {
+        let socket = match UdpSocket::bind(("0.0.0.0", cfg.port)) {
+            Ok(socket) => socket,
+            Err(error) => {
+                error!(
+                    "Unable to listen for JSON tracking on udp port:{}: {}",
+                    cfg.port, error
+                );
+                return;
+            }
+        };
+        let _ = socket.set_read_timeout(Some(time::Duration::new(2, 0)));
+        info!("Listening for JSON tracking on udp port:{}", cfg.port);
+
+        let mut buf = [0; 65536];
+
+        while active.load(Ordering::Relaxed) {
+            match socket.recv_from(&mut buf) {
+                Ok((amt, _src)) => match serde_json::from_slice::<Value>(&buf[..amt]) {
+                    Ok(value) => {
+                        if sender.send(frame(cfg, &value)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(error) => {
+                        warn!("Unnable to deserialize: {}", error)
+                    }
+                },
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut => {}
+                Err(error) => {
+                    warn!("Unnable to receive JSON tracking: {}", error)
+                }
+            }
+        }
+    }
+
+    /// Local websocket server, one client at a time
+    fn run_websocket(
+        cfg: &JsonInputCfg,
+        sender: &SlotSender<TrackingResponce>,
+        active: &AtomicBool,
+    ) {
+        let listener = match TcpListener::bind(("127.0.0.1", cfg.port)) {
+            Ok(listener) => listener,
+            Err(error) => {
+                error!(
+                    "Unable to listen for JSON tracking on ws://localhost:{}: {}",
+                    cfg.port, error
+                );
+                return;
+            }
+        };
+        let _ = listener.set_nonblocking(true);
+        info!("Listening for JSON tracking on ws://localhost:{}", cfg.port);
+
+        while active.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("JSON tracking client connected: {}", addr);
+                    if !JsonInput::serve_client(cfg, stream, sender, active) {
+                        return;
+                    }
+                    info!("JSON tracking client disconnected: {}", addr);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(100));
+                }
+                Err(error) => {
+                    warn!("Unnable to accept JSON tracking client: {}", error)
+                }
+            }
+        }
+    }
+
+    /// Returns false when frames receiver is gone
+    fn serve_client(
+        cfg: &JsonInputCfg,
+        stream: TcpStream,
+        sender: &SlotSender<TrackingResponce>,
+        active: &AtomicBool,
+    ) -> bool {
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(time::Duration::new(2, 0)));
+
+        let mut websocket = match tungstenite::accept(stream) {
+            Ok(ws) => ws,
+            Err(error) => {
+                warn!("Websocket handshake failed: {}", error);
+                return true;
+            }
+        };
+
+        while active.load(Ordering::Relaxed) {
+            match websocket.read() {
+                Ok(msg) => {
+                    if msg.is_close() {
+                        return true;
+                    }
+                    if !msg.is_text() && !msg.is_binary() {
+                        continue;
+                    }
+                    match serde_json::from_slice::<Value>(&msg.into_data()) {
+                        Ok(value) => {
+                            if sender.send(frame(cfg, &value)).is_err() {
+                                return false;
+                            }
+                        }
+                        Err(error) => {
+                            warn!("Unnable to deserialize: {}", error)
+                        }
+                    }
+                }
+                Err(tungstenite::Error::Io(ref e))
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(error) => {
+                    warn!("Unable to read msg: {}", error);
+                    return true;
+                }
+            }
+        }
+        true
+    }
+}
+
+fn frame(cfg: &JsonInputCfg, value: &Value) -> TrackingResponce {
+    let number = |pointer: &str| -> Option<f64> {
+        match value.pointer(pointer)? {
+            Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            v => v.as_f64(),
+        }
+    };
+
+    let mut frame = TrackingResponce {
+        timestamp: cfg
+            .timestamp
+            .as_deref()
+            .and_then(number)
+            .map_or_else(now_millis, |t| t as u64),
+        hotkey: -1,
+        face_found: cfg.face_found.as_deref().and_then(number) != Some(0.0),
+        rotation: Default::default(),
+        position: Default::default(),
+        eye_left: Default::default(),
+        blend_shapes: BlendShapes::default(),
+        received_at: Some(time::Instant::now()),
+    };
+
+    for (name, pointer) in &cfg.fields {
+        let v = match number(pointer) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        match name.as_str() {
+            "HeadRotX" => frame.rotation.x = v,
+            "HeadRotY" => frame.rotation.y = v,
+            "HeadRotZ" => frame.rotation.z = v,
+            "HeadPosX" => frame.position.x = v,
+            "HeadPosY" => frame.position.y = v,
+            "HeadPosZ" => frame.position.z = v,
+            _ => frame.blend_shapes.insert(name, v),
+        }
+    }
+
+    frame
+}
@@ -1,2 +1,20 @@
+pub mod bytes;
+pub mod config;
+pub mod health;
+pub mod hotkeys;
+pub mod jsoninput;
+pub mod livelink;
+pub mod merge;
+pub mod mockphone;
+pub mod osc;
+pub mod phoneserver;
+pub mod pipeline;
+pub mod relay;
+pub mod sink;
+pub mod slot;
+pub mod transform;
+pub mod tunnel;
+pub mod vmc;
+pub mod vrchat;
 pub mod vtspc;
 pub mod vtsphone;
\ No newline at end of file
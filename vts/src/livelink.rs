@@ -0,0 +1,468 @@
+use std::{
+    fs, io,
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    bytes::{read_u32, take},
+    slot::SlotSender,
+    vtsphone::{now_millis, BlendShapes, Cords, TrackingResponce},
+};
+
+/// Order of values in a Live Link Face packet
+pub const LIVE_LINK_CURVES: [&str; 61] = [
+    "EyeBlinkLeft",
+    "EyeLookDownLeft",
+    "EyeLookInLeft",
+    "EyeLookOutLeft",
+    "EyeLookUpLeft",
+    "EyeSquintLeft",
+    "EyeWideLeft",
+    "EyeBlinkRight",
+    "EyeLookDownRight",
+    "EyeLookInRight",
+    "EyeLookOutRight",
+    "EyeLookUpRight",
+    "EyeSquintRight",
+    "EyeWideRight",
+    "JawForward",
+    "JawLeft",
+    "JawRight",
+    "JawOpen",
+    "MouthClose",
+    "MouthFunnel",
+    "MouthPucker",
+    "MouthLeft",
+    "MouthRight",
+    "MouthSmileLeft",
+    "MouthSmileRight",
+    "MouthFrownLeft",
+    "MouthFrownRight",
+    "MouthDimpleLeft",
+    "MouthDimpleRight",
+    "MouthStretchLeft",
+    "MouthStretchRight",
+    "MouthRollLower",
+    "MouthRollUpper",
+    "MouthShrugLower",
+    "MouthShrugUpper",
+    "MouthPressLeft",
+    "MouthPressRight",
+    "MouthLowerDownLeft",
+    "MouthLowerDownRight",
+    "MouthUpperUpLeft",
+    "MouthUpperUpRight",
+    "BrowDownLeft",
+    "BrowDownRight",
+    "BrowInnerUp",
+    "BrowOuterUpLeft",
+    "BrowOuterUpRight",
+    "CheekPuff",
+    "CheekSquintLeft",
+    "CheekSquintRight",
+    "NoseSneerLeft",
+    "NoseSneerRight",
+    "TongueOut",
+    "HeadYaw",
+    "HeadPitch",
+    "HeadRoll",
+    "LeftEyeYaw",
+    "LeftEyePitch",
+    "LeftEyeRoll",
+    "RightEyeYaw",
+    "RightEyePitch",
+    "RightEyeRoll",
+];
+
+/// Input from Unreal Live Link Face app (UDP stream or CSV takes)
+pub struct LiveLinkFace;
+
+impl LiveLinkFace {
+    pub fn run(port: u16, sender: SlotSender<TrackingResponce>, active: Arc<AtomicBool>) {
+        let socket = match UdpSocket::bind(("0.0.0.0", port)) {
+            Ok(socket) => socket,
+            Err(error) => {
+                error!(
+                    "Unable to listen for Live Link Face on port:{}: {}",
+                    port, error
+                );
+                return;
+            }
+        };
+        let _ = socket.set_read_timeout(Some(time::Duration::new(2, 0)));
+        info!("Listening for Live Link Face on port:{}", port);
+
+        let mut buf = [0; 4096];
+
+        while active.load(Ordering::Relaxed) {
+            match socket.recv_from(&mut buf) {
+                Ok((amt, _src)) => match decode(&buf[..amt]) {
+                    Ok(Some(values)) => {
+                        let curves = LIVE_LINK_CURVES.iter().copied().zip(values);
+                        if sender.send(frame(curves, now_millis())).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => {
+                        // Subject without frame data, sent when face is lost
+                    }
+                    Err(error) => {
+                        warn!("Unnable to decode Live Link Face packet: {}", error)
+                    }
+                },
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut => {}
+                Err(error) => {
+                    warn!("Unnable to receive Live Link Face: {}", error)
+                }
+            }
+        }
+    }
+
+    /// Replay CSV take recorded by Live Link Face with its original timing
+    pub fn replay(
+        path: String,
+        looping: bool,
+        sender: SlotSender<TrackingResponce>,
+        active: Arc<AtomicBool>,
+    ) {
+        info!("Loading Live Link Face take: {}", path);
+        let take = match read_csv(&path) {
+            Ok(t) => t,
+            Err(error) => {
+                warn!("Unable to read Live Link Face take: {}", error);
+                return;
+            }
+        };
+        info!("Live Link Face take loaded: {} frames", take.len());
+
+        while active.load(Ordering::Relaxed) {
+            let start = time::Instant::now();
+            let first = take.first().map_or(0.0, |f| f.0);
+
+            for (time_code, values) in &take {
+                if !active.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let at = start + time::Duration::from_secs_f64((time_code - first).max(0.0));
+                if let Some(wait) = at.checked_duration_since(time::Instant::now()) {
+                    thread::sleep(wait);
+                }
+
+                let curves = values.iter().map(|(k, v)| (k.as_str(), *v));
+                if sender.send(frame(curves, now_millis())).is_err() {
+                    return;
+                }
+            }
+
+            if !looping {
+                info!("Live Link Face take finished");
+                return;
+            }
+        }
+    }
+}
+
+/// Decode Live Link Face packet into values ordered as `LIVE_LINK_CURVES`
+///
+/// Packet is big endian: version (u8), device id and subject name (i32 length + utf8),
+/// frame time (i32 frame, f32 subframe, i32 fps numerator, i32 fps denominator),
+/// then values count (u8) and values (f32)
+pub fn decode(buf: &[u8]) -> Result<Option<Vec<f64>>, String> {
+    let mut pos = 1;
+    for _ in 0..2 {
+        let len = read_u32(buf, &mut pos)? as usize;
+        take(buf, &mut pos, len)?;
+    }
+
+    if pos == buf.len() {
+        return Ok(None);
+    }
+
+    // frame time and frame rate
+    take(buf, &mut pos, 16)?;
+
+    let count = take(buf, &mut pos, 1)?[0] as usize;
+    if count != LIVE_LINK_CURVES.len() {
+        return Err(format!("Unexpected values count: {}", count));
+    }
+
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        values.push(f32::from_bits(read_u32(buf, &mut pos)?) as f64);
+    }
+    Ok(Some(values))
+}
+
+type TakeFrame = (f64, Vec<(String, f64)>);
+
+/// Read Live Link Face CSV take, returns frames with time in seconds
+pub fn read_csv(path: &str) -> Result<Vec<TakeFrame>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut lines = content.lines();
+    let header: Vec<&str> = match lines.next() {
+        Some(h) => h.split(',').map(str::trim).collect(),
+        None => return Err("Empty take".to_string()),
+    };
+
+    let mut rows = Vec::new();
+    for (n, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut time_code = None;
+        let mut values = Vec::new();
+        for (name, cell) in header.iter().zip(line.split(',')) {
+            match *name {
+                "Timecode" => time_code = parse_time_code(cell.trim()),
+                "BlendShapeCount" => {}
+                _ => match cell.trim().parse::<f64>() {
+                    Ok(v) => values.push((name.to_string(), v)),
+                    Err(e) => return Err(format!("Line {}: {}: {}", n + 2, name, e)),
+                },
+            }
+        }
+
+        rows.push((time_code, values));
+    }
+
+    let fps = frame_rate(rows.iter().filter_map(|(time_code, _)| *time_code));
+    info!("Live Link Face take frame rate: {} fps", fps);
+
+    let mut take = Vec::new();
+    let mut last_time = 0.0;
+    for (time_code, values) in rows {
+        // Next frame when timecode is missing
+        last_time = match time_code {
+            Some(TimeCode([h, m, s, f])) => h * 3600.0 + m * 60.0 + s + f / fps,
+            None => last_time + 1.0 / fps,
+        };
+        take.push((last_time, values));
+    }
+
+    Ok(take)
+}
+
+/// Timecode `HH:MM:SS:FF.fff` as hours, minutes, seconds and frames
+#[derive(Clone, Copy)]
+struct TimeCode([f64; 4]);
+
+fn parse_time_code(time_code: &str) -> Option<TimeCode> {
+    let parts: Vec<f64> = time_code
+        .split(':')
+        .map(|p| p.parse::<f64>().ok())
+        .collect::<Option<_>>()?;
+
+    match parts[..] {
+        [h, m, s, f] => Some(TimeCode([h, m, s, f])),
+        _ => None,
+    }
+}
+
+/// Common frame rates of Live Link Face takes
+const FRAME_RATES: [f64; 6] = [24.0, 25.0, 30.0, 48.0, 50.0, 60.0];
+
+/// Frame rate from the highest frame number before seconds change,
+/// for takes shorter than a second the lowest common rate above every frame number
+fn frame_rate(time_codes: impl Iterator<Item = TimeCode>) -> f64 {
+    let mut last: Option<TimeCode> = None;
+    let mut max_frame: f64 = 0.0;
+    let mut wrapped: Option<f64> = None;
+
+    for time_code in time_codes {
+        let frame = time_code.0[3].floor();
+        if let Some(last) = last {
+            if last.0[..3] != time_code.0[..3] {
+                let before = last.0[3].floor();
+                wrapped = Some(wrapped.map_or(before, |w| w.max(before)));
+            }
+        }
+        max_frame = max_frame.max(frame);
+        last = Some(time_code);
+    }
+
+    match wrapped {
+        Some(before) => before + 1.0,
+        None => FRAME_RATES
+            .iter()
+            .copied()
+            .find(|rate| *rate > max_frame)
+            .unwrap_or(max_frame + 1.0),
+    }
+}
+
+fn frame<'a>(curves: impl Iterator<Item = (&'a str, f64)>, timestamp: u64) -> TrackingResponce {
+    let mut rotation = Cords::default();
+    let mut eye_left = Cords::default();
+    let mut blend_shapes = BlendShapes::default();
+
+    // Rotations are in radians
+    for (name, value) in curves {
+        match name {
+            "HeadPitch" => rotation.x = value.to_degrees(),
+            "HeadYaw" => rotation.y = value.to_degrees(),
+            "HeadRoll" => rotation.z = value.to_degrees(),
+            "LeftEyePitch" => eye_left.x = value.to_degrees(),
+            "LeftEyeYaw" => eye_left.y = value.to_degrees(),
+            "LeftEyeRoll" => eye_left.z = value.to_degrees(),
+            "RightEyePitch" | "RightEyeYaw" | "RightEyeRoll" => {}
+            _ => blend_shapes.insert(name, value),
+        }
+    }
+
+    TrackingResponce {
+        timestamp,
+        hotkey: -1,
+        face_found: true,
+        rotation,
+        position: Cords::default(),
+        eye_left,
+        blend_shapes,
+        received_at: Some(time::Instant::now()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Live Link Face packet as sent by the app, `values` empty for a handshake
+    fn packet(values: &[f32]) -> Vec<u8> {
+        let mut buf = vec![6];
+        for name in ["A1B2C3D4-0000-0000-0000-000000000000", "iPhone"] {
+            buf.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            buf.extend_from_slice(name.as_bytes());
+        }
+        if values.is_empty() {
+            return buf;
+        }
+
+        // frame 1234, subframe 0.5, 60/1 fps
+        buf.extend_from_slice(&1234i32.to_be_bytes());
+        buf.extend_from_slice(&0.5f32.to_be_bytes());
+        buf.extend_from_slice(&60i32.to_be_bytes());
+        buf.extend_from_slice(&1i32.to_be_bytes());
+        buf.push(values.len() as u8);
+        for value in values {
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+        buf
+    }
+
+    fn time_codes(time_codes: &[&str]) -> Vec<TimeCode> {
+        time_codes
+            .iter()
+            .map(|t| parse_time_code(t).unwrap())
+            .collect()
+    }
+
+    fn write_take(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.csv", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn decodes_values() {
+        let values: Vec<f32> = (0..LIVE_LINK_CURVES.len())
+            .map(|i| i as f32 / 100.0)
+            .collect();
+        let decoded = decode(&packet(&values)).unwrap().unwrap();
+
+        assert_eq!(decoded.len(), LIVE_LINK_CURVES.len());
+        for (decoded, value) in decoded.iter().zip(&values) {
+            assert_eq!(*decoded, *value as f64);
+        }
+    }
+
+    #[test]
+    fn handshake_has_no_values() {
+        assert_eq!(decode(&packet(&[])).unwrap(), None);
+    }
+
+    #[test]
+    fn wrong_count_and_truncated_are_rejected() {
+        assert!(decode(&packet(&[0.5; 52])).is_err());
+
+        let full = packet(&[0.5; LIVE_LINK_CURVES.len()]);
+        assert!(decode(&full[..full.len() - 2]).is_err());
+        assert!(decode(&full[..3]).is_err());
+    }
+
+    #[test]
+    fn frame_rate_from_wrapping_frames() {
+        let codes = time_codes(&[
+            "00:00:00:58.2",
+            "00:00:00:59.1",
+            "00:00:01:00.0",
+            "00:00:01:01.5",
+        ]);
+        assert_eq!(frame_rate(codes.into_iter()), 60.0);
+
+        let codes = time_codes(&["00:00:09:28", "00:00:09:29", "00:00:10:00"]);
+        assert_eq!(frame_rate(codes.into_iter()), 30.0);
+    }
+
+    #[test]
+    fn frame_rate_of_short_take() {
+        let codes = time_codes(&["00:00:00:10", "00:00:00:11", "00:00:00:26"]);
+        assert_eq!(frame_rate(codes.into_iter()), 30.0);
+
+        let codes = time_codes(&["00:00:00:00", "00:00:00:01"]);
+        assert_eq!(frame_rate(codes.into_iter()), 24.0);
+    }
+
+    #[test]
+    fn reads_take_timecodes() {
+        let path = write_take(
+            "livelink-take",
+            "Timecode,BlendShapeCount,EyeBlinkLeft,JawOpen\n\
+             01:02:03:28.5,61,0.1,0.2\n\
+             01:02:03:29.5,61,0.3,0.4\n\
+             \n\
+             bad,61,0.5,0.6\n\
+             01:02:04:00.5,61,0.7,0.8\n",
+        );
+        let take = read_csv(&path);
+        let _ = fs::remove_file(&path);
+        let take = take.unwrap();
+
+        let start = 3600.0 + 2.0 * 60.0 + 3.0;
+        let times: Vec<f64> = take.iter().map(|(time, _)| time - start).collect();
+        let expected = [28.5 / 30.0, 29.5 / 30.0, 30.5 / 30.0, 1.0 + 0.5 / 30.0];
+        assert_eq!(times.len(), expected.len());
+        for (time, expected) in times.iter().zip(expected) {
+            assert!((time - expected).abs() < 1e-9, "{} != {}", time, expected);
+        }
+
+        assert_eq!(
+            take[0].1,
+            vec![
+                ("EyeBlinkLeft".to_string(), 0.1),
+                ("JawOpen".to_string(), 0.2)
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_take_value_is_rejected() {
+        let path = write_take("livelink-bad", "Timecode,JawOpen\n00:00:00:00,abc\n");
+        let take = read_csv(&path);
+        let _ = fs::remove_file(&path);
+        assert!(take.unwrap_err().starts_with("Line 2: JawOpen"));
+    }
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+
+use crate::{
+    slot::{SlotReceiver, SlotSender},
+    vtsphone::{now_millis, BlendShape, BlendShapes, Cords, TrackingResponce},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum MergePolicy {
+    /// Value from the highest priority source that has it
+    #[default]
+    Primary,
+    /// Average of all sources using their weights
+    Average,
+    /// Biggest value of all sources
+    Max,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeCfg {
+    #[serde(default)]
+    pub policy: MergePolicy,
+    /// Policy for specific variables (`JawOpen`, `HeadRotX`, ...)
+    #[serde(default)]
+    pub variables: HashMap<String, MergePolicy>,
+    /// Source without frames for this long is skipped until it sends again
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Frames received longer than this ago are not sent, `null` to disable
+    #[serde(default = "default_max_age_ms")]
+    pub max_age_ms: Option<u64>,
+}
+
+fn default_timeout_ms() -> u64 {
+    500
+}
+
+fn default_max_age_ms() -> Option<u64> {
+    Some(1000)
+}
+
+impl Default for MergeCfg {
+    fn default() -> Self {
+        MergeCfg {
+            policy: MergePolicy::default(),
+            variables: HashMap::new(),
+            timeout_ms: default_timeout_ms(),
+            max_age_ms: default_max_age_ms(),
+        }
+    }
+}
+
+pub struct Source {
+    pub name: String,
+    pub weight: f64,
+    pub receiver: SlotReceiver<TrackingResponce>,
+}
+
+struct SourceState {
+    source: Source,
+    frame: Option<TrackingResponce>,
+    /// Timestamp of the last applied frame
+    last_timestamp: Option<u64>,
+    last_seen: Option<Instant>,
+    live: bool,
+}
+
+/// Combines frames of several inputs into one
+///
+/// Sources are in priority order, first one is primary
+pub struct Merger {
+    cfg: MergeCfg,
+    sources: Vec<SourceState>,
+    /// Timestamp of the last merged frame
+    timestamp: u64,
+}
+
+impl Merger {
+    pub fn new(cfg: MergeCfg, sources: Vec<Source>) -> Merger {
+        Merger {
+            cfg,
+            sources: sources
+                .into_iter()
+                .map(|source| SourceState {
+                    source,
+                    frame: None,
+                    last_timestamp: None,
+                    last_seen: None,
+                    live: false,
+                })
+                .collect(),
+            timestamp: 0,
+        }
+    }
+
+    pub fn single(receiver: SlotReceiver<TrackingResponce>) -> Merger {
+        Merger::new(
+            MergeCfg::default(),
+            vec![Source {
+                name: "input".to_string(),
+                weight: 1.0,
+                receiver,
+            }],
+        )
+    }
+
+    /// Poll on own thread and hand every merged frame to all senders, for several outputs
+    pub fn fan_out(mut self, senders: Vec<SlotSender<TrackingResponce>>, active: Arc<AtomicBool>) {
+        while active.load(Ordering::Relaxed) {
+            match self.poll() {
+                Some(frame) => {
+                    let sent = senders.iter().filter(|s| s.send(frame.clone()).is_ok());
+                    if sent.count() == 0 {
+                        return;
+                    }
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    /// Merged frame, if any live source sent a new one since last poll
+    pub fn poll(&mut self) -> Option<TrackingResponce> {
+        let now = Instant::now();
+        let timeout = Duration::from_millis(self.cfg.timeout_ms);
+        let max_age = self.cfg.max_age_ms.map(Duration::from_millis);
+        let too_old = |frame: &TrackingResponce| match (max_age, frame.received_at) {
+            (Some(max_age), Some(received_at)) => now - received_at > max_age,
+            _ => false,
+        };
+        let mut updated = false;
+
+        for state in &mut self.sources {
+            if let Some(frame) = state.source.receiver.try_recv() {
+                if state.last_timestamp.is_some_and(|t| frame.timestamp <= t) {
+                    debug!("Input {}: dropped stale frame", state.source.name);
+                } else if too_old(&frame) {
+                    debug!("Input {}: dropped too old frame", state.source.name);
+                } else {
+                    state.last_timestamp = Some(frame.timestamp);
+                    state.frame = Some(frame);
+                    state.last_seen = Some(now);
+                    updated = true;
+                }
+            }
+
+            let live = state.last_seen.is_some_and(|t| now - t < timeout);
+            if live != state.live {
+                if live {
+                    info!("Input {} is streaming", state.source.name);
+                } else {
+                    info!("Input {} went silent, failing over", state.source.name);
+                    // Source may restart with a different clock
+                    state.last_timestamp = None;
+                }
+                state.live = live;
+            }
+        }
+
+        if !updated {
+            return None;
+        }
+
+        if self.sources.len() == 1 {
+            return self.sources[0].frame.clone();
+        }
+
+        let live: Vec<(f64, &TrackingResponce)> = self
+            .sources
+            .iter()
+            .filter(|s| s.live)
+            .filter_map(|s| s.frame.as_ref().map(|f| (s.source.weight, f)))
+            .filter(|(_, f)| !too_old(f))
+            .collect();
+
+        let found: Vec<(f64, &TrackingResponce)> =
+            live.iter().copied().filter(|(_, f)| f.face_found).collect();
+
+        let mut frame = match (found.is_empty(), live.first()) {
+            (true, Some((_, primary))) => (*primary).clone(),
+            (true, None) => return None,
+            (false, _) => self.merge(&found),
+        };
+        // Sources have own clocks and may update alone, so merged frames use local clock
+        self.timestamp = now_millis().max(self.timestamp + 1);
+        frame.timestamp = self.timestamp;
+        Some(frame)
+    }
+
+    fn merge(&self, frames: &[(f64, &TrackingResponce)]) -> TrackingResponce {
+        let primary = frames[0].1;
+
+        let cords = |name: &str, get: fn(&TrackingResponce) -> f64| -> f64 {
+            let values: Vec<(f64, f64)> = frames.iter().map(|(w, f)| (*w, get(f))).collect();
+            self.apply(name, &values)
+        };
+
+        let mut blend_shapes = BlendShapes::default();
+        let mut values: Vec<(f64, f64)> = Vec::with_capacity(frames.len());
+
+        for shape in BlendShape::ALL {
+            values.clear();
+            values.extend(
+                frames
+                    .iter()
+                    .filter_map(|(w, f)| f.blend_shapes.get(shape).map(|v| (*w, v))),
+            );
+            if !values.is_empty() {
+                blend_shapes.set(shape, self.apply(shape.name(), &values));
+            }
+        }
+
+        let mut custom: Vec<(&str, Vec<(f64, f64)>)> = Vec::new();
+        for (weight, frame) in frames {
+            for shape in &frame.blend_shapes.custom {
+                match custom.iter_mut().find(|(k, _)| *k == shape.k) {
+                    Some((_, values)) => values.push((*weight, shape.v)),
+                    None => custom.push((&shape.k, vec![(*weight, shape.v)])),
+                }
+            }
+        }
+        for (k, values) in custom {
+            blend_shapes.insert(k, self.apply(k, &values));
+        }
+
+        TrackingResponce {
+            timestamp: primary.timestamp,
+            hotkey: primary.hotkey,
+            face_found: true,
+            rotation: Cords {
+                x: cords("HeadRotX", |f| f.rotation.x),
+                y: cords("HeadRotY", |f| f.rotation.y),
+                z: cords("HeadRotZ", |f| f.rotation.z),
+            },
+            position: Cords {
+                x: cords("HeadPosX", |f| f.position.x),
+                y: cords("HeadPosY", |f| f.position.y),
+                z: cords("HeadPosZ", |f| f.position.z),
+            },
+            eye_left: Cords {
+                x: cords("EyeLeftX", |f| f.eye_left.x),
+                y: cords("EyeLeftY", |f| f.eye_left.y),
+                z: cords("EyeLeftZ", |f| f.eye_left.z),
+            },
+            blend_shapes,
+            received_at: primary.received_at,
+        }
+    }
+
+    /// `values` are `(weight, value)` in priority order, never empty
+    fn apply(&self, name: &str, values: &[(f64, f64)]) -> f64 {
+        let policy = self
+            .cfg
+            .variables
+            .get(name)
+            .copied()
+            .unwrap_or(self.cfg.policy);
+
+        match policy {
+            MergePolicy::Primary => values[0].1,
+            MergePolicy::Average => {
+                let total: f64 = values.iter().map(|(w, _)| w).sum();
+                if total == 0.0 {
+                    return values[0].1;
+                }
+                values.iter().map(|(w, v)| w * v).sum::<f64>() / total
+            }
+            MergePolicy::Max => values
+                .iter()
+                .map(|(_, v)| *v)
+                .fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slot::slot;
+
+    fn frame(timestamp: u64, jaw_open: f64) -> TrackingResponce {
+        let mut blend_shapes = BlendShapes::default();
+        blend_shapes.set(BlendShape::JawOpen, jaw_open);
+        TrackingResponce {
+            timestamp,
+            hotkey: -1,
+            face_found: true,
+            rotation: Cords::default(),
+            position: Cords::default(),
+            eye_left: Cords::default(),
+            blend_shapes,
+            received_at: Some(Instant::now()),
+        }
+    }
+
+    fn merger(cfg: MergeCfg, weights: &[f64]) -> (Merger, Vec<SlotSender<TrackingResponce>>) {
+        let mut senders = Vec::new();
+        let mut sources = Vec::new();
+        for (i, weight) in weights.iter().enumerate() {
+            let (sender, receiver) = slot();
+            senders.push(sender);
+            sources.push(Source {
+                name: format!("input{}", i),
+                weight: *weight,
+                receiver,
+            });
+        }
+        (Merger::new(cfg, sources), senders)
+    }
+
+    #[test]
+    fn merged_timestamp_grows() {
+        let (mut merger, senders) = merger(MergeCfg::default(), &[1.0, 1.0]);
+
+        // backup clock is far behind primary
+        senders[0].send(frame(5_000_000_000_000, 0.1)).unwrap();
+        senders[1].send(frame(10, 0.2)).unwrap();
+        let first = merger.poll().unwrap();
+
+        // only backup updates
+        senders[1].send(frame(11, 0.3)).unwrap();
+        let second = merger.poll().unwrap();
+        assert!(second.timestamp > first.timestamp);
+
+        // merged frames pass stale check of the next merger
+        let (sender, receiver) = slot();
+        let mut single = Merger::single(receiver);
+        sender.send(first).unwrap();
+        assert!(single.poll().is_some());
+        sender.send(second).unwrap();
+        assert!(single.poll().is_some());
+    }
+
+    fn jaw_open(frame: &TrackingResponce) -> f64 {
+        frame.blend_shapes.get(BlendShape::JawOpen).unwrap()
+    }
+
+    fn assert_close(value: f64, expected: f64) {
+        assert!((value - expected).abs() < 1e-9, "{} != {}", value, expected);
+    }
+
+    fn merged(policy: MergePolicy) -> TrackingResponce {
+        let cfg = MergeCfg {
+            policy,
+            ..MergeCfg::default()
+        };
+        let (mut merger, senders) = merger(cfg, &[3.0, 1.0]);
+        senders[0].send(frame(1, 0.2)).unwrap();
+        senders[1].send(frame(1, 0.6)).unwrap();
+        merger.poll().unwrap()
+    }
+
+    #[test]
+    fn policies() {
+        assert_close(jaw_open(&merged(MergePolicy::Primary)), 0.2);
+        assert_close(jaw_open(&merged(MergePolicy::Average)), 0.3);
+        assert_close(jaw_open(&merged(MergePolicy::Max)), 0.6);
+    }
+
+    #[test]
+    fn variable_policy_overrides_default() {
+        let cfg = MergeCfg {
+            policy: MergePolicy::Average,
+            variables: HashMap::from([("JawOpen".to_string(), MergePolicy::Max)]),
+            ..MergeCfg::default()
+        };
+        let (mut merger, senders) = merger(cfg, &[1.0, 1.0]);
+
+        let mut primary = frame(1, 0.2);
+        primary.rotation.x = 10.0;
+        let mut backup = frame(1, 0.6);
+        backup.rotation.x = 20.0;
+        senders[0].send(primary).unwrap();
+        senders[1].send(backup).unwrap();
+
+        let frame = merger.poll().unwrap();
+        assert_close(jaw_open(&frame), 0.6);
+        assert_close(frame.rotation.x, 15.0);
+    }
+
+    #[test]
+    fn zero_weights_use_primary() {
+        let cfg = MergeCfg {
+            policy: MergePolicy::Average,
+            ..MergeCfg::default()
+        };
+        let (merger, _senders) = merger(cfg, &[0.0, 0.0]);
+        assert_close(merger.apply("JawOpen", &[(0.0, 0.2), (0.0, 0.6)]), 0.2);
+    }
+
+    #[test]
+    fn missing_values_come_from_other_sources() {
+        let (mut merger, senders) = merger(MergeCfg::default(), &[1.0, 1.0]);
+
+        let mut primary = frame(1, 0.2);
+        primary.blend_shapes = BlendShapes::default();
+        primary.blend_shapes.insert("Custom", 1.0);
+        let mut backup = frame(1, 0.6);
+        backup.blend_shapes.insert("Custom", 2.0);
+        senders[0].send(primary).unwrap();
+        senders[1].send(backup).unwrap();
+
+        let frame = merger.poll().unwrap();
+        assert_close(jaw_open(&frame), 0.6);
+        let custom = frame.blend_shapes.custom.iter().find(|s| s.k == "Custom");
+        assert_close(custom.unwrap().v, 1.0);
+    }
+
+    #[test]
+    fn lost_face_is_skipped() {
+        let (mut merger, senders) = merger(MergeCfg::default(), &[1.0, 1.0]);
+
+        let mut primary = frame(1, 0.2);
+        primary.face_found = false;
+        senders[0].send(primary).unwrap();
+        senders[1].send(frame(1, 0.6)).unwrap();
+        assert_close(jaw_open(&merger.poll().unwrap()), 0.6);
+    }
+
+    #[test]
+    fn fails_over_to_live_source() {
+        let cfg = MergeCfg {
+            timeout_ms: 50,
+            ..MergeCfg::default()
+        };
+        let (mut merger, senders) = merger(cfg, &[1.0, 1.0]);
+
+        senders[0].send(frame(100, 0.2)).unwrap();
+        senders[1].send(frame(100, 0.6)).unwrap();
+        assert_close(jaw_open(&merger.poll().unwrap()), 0.2);
+
+        // primary went silent
+        thread::sleep(Duration::from_millis(80));
+        senders[1].send(frame(101, 0.7)).unwrap();
+        assert_close(jaw_open(&merger.poll().unwrap()), 0.7);
+
+        // primary restarted with its clock behind
+        senders[0].send(frame(1, 0.3)).unwrap();
+        assert_close(jaw_open(&merger.poll().unwrap()), 0.3);
+    }
+
+    #[test]
+    fn stale_frame_is_dropped() {
+        let (sender, receiver) = slot();
+        let mut merger = Merger::single(receiver);
+
+        sender.send(frame(5, 0.2)).unwrap();
+        assert!(merger.poll().is_some());
+        sender.send(frame(5, 0.3)).unwrap();
+        assert!(merger.poll().is_none());
+        sender.send(frame(4, 0.3)).unwrap();
+        assert!(merger.poll().is_none());
+    }
+}
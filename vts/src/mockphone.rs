@@ -0,0 +1,152 @@
+use std::{
+    f64::consts::TAU,
+    fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use log::info;
+
+use crate::{
+    phoneserver::PhoneServer,
+    vtsphone::{now_millis, BlendShape, BlendShapes, Cords, TrackingResponce},
+};
+
+/// Frames streamed by mock phone
+pub enum MockFrames {
+    /// Head turning, talking and blinking, at given frames per second
+    Synthetic { fps: u32 },
+    /// Frames recorded with `record` hotkey action, with original timing
+    Recording {
+        frames: Vec<TrackingResponce>,
+        looping: bool,
+    },
+}
+
+impl MockFrames {
+    /// Read recording, one JSON frame per line
+    pub fn read_recording(path: &str, looping: bool) -> Result<MockFrames, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let frames = content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e))
+            })
+            .collect::<Result<Vec<TrackingResponce>, String>>()?;
+
+        if frames.is_empty() {
+            return Err(format!("{}: no frames", path));
+        }
+        Ok(MockFrames::Recording { frames, looping })
+    }
+}
+
+/// Phone for tests and demos without iPhone, answers tracking requests like
+/// VTubeStudio app and streams frames only while someone requested them
+pub struct MockPhone;
+
+impl MockPhone {
+    pub fn run(port: u16, frames: MockFrames, active: Arc<AtomicBool>) -> Result<(), String> {
+        let mut server = PhoneServer::bind(port)?;
+        info!("Mock phone listening on port:{}", server.port());
+
+        let start = time::Instant::now();
+        let mut next_time = time::Instant::now();
+        let mut index = 0;
+
+        while active.load(Ordering::Relaxed) {
+            server.poll_requests();
+
+            if !server.has_clients() {
+                thread::sleep(time::Duration::from_millis(10));
+                next_time = time::Instant::now();
+                continue;
+            }
+
+            if let Some(wait) = next_time.checked_duration_since(time::Instant::now()) {
+                // Short sleeps so new requests are answered in time
+                thread::sleep(wait.min(time::Duration::from_millis(10)));
+                continue;
+            }
+
+            let frame = match &frames {
+                MockFrames::Synthetic { fps } => {
+                    next_time += time::Duration::from_secs_f64(1.0 / (*fps).max(1) as f64);
+                    synthetic(start.elapsed().as_secs_f64())
+                }
+                MockFrames::Recording { frames, looping } => {
+                    if index == frames.len() {
+                        if !looping {
+                            info!("Recording finished");
+                            return Ok(());
+                        }
+                        index = 0;
+                    }
+                    let mut frame = frames[index].clone();
+                    index += 1;
+
+                    let next = frames.get(index).unwrap_or(&frames[0]);
+                    let delay = next.timestamp.saturating_sub(frame.timestamp).min(1000);
+                    next_time += time::Duration::from_millis(delay);
+
+                    // Looped frames must still look newer than previous ones
+                    frame.timestamp = now_millis();
+                    frame
+                }
+            };
+
+            server.send(&frame);
+        }
+
+        Ok(())
+    }
+}
+
+/// Frame at `t` seconds, every ARKit shape is set like from real phone
+fn synthetic(t: f64) -> TrackingResponce {
+    let mut blend_shapes = BlendShapes::default();
+    for shape in BlendShape::ALL {
+        blend_shapes.set(shape, 0.0);
+    }
+
+    let wave = |period: f64| (t * TAU / period).sin();
+    let talk = ((wave(0.4) + 1.0) / 2.0) * ((wave(3.0) + 1.0) / 2.0);
+    let blink = if t % 4.0 < 0.15 { 1.0 } else { 0.0 };
+    let smile = (wave(7.0) + 1.0) / 2.0;
+
+    blend_shapes.set(BlendShape::JawOpen, talk * 0.6);
+    blend_shapes.set(BlendShape::MouthFunnel, talk * 0.2);
+    blend_shapes.set(BlendShape::EyeBlinkLeft, blink);
+    blend_shapes.set(BlendShape::EyeBlinkRight, blink);
+    blend_shapes.set(BlendShape::MouthSmileLeft, smile * 0.7);
+    blend_shapes.set(BlendShape::MouthSmileRight, smile * 0.7);
+    blend_shapes.set(BlendShape::BrowInnerUp, (1.0 - smile) * 0.3);
+
+    TrackingResponce {
+        timestamp: now_millis(),
+        hotkey: -1,
+        face_found: true,
+        rotation: Cords {
+            x: wave(5.0) * 10.0,
+            y: wave(6.0) * 25.0,
+            z: wave(9.0) * 8.0,
+        },
+        position: Cords {
+            x: wave(6.0) * 2.0,
+            y: wave(11.0),
+            z: 0.0,
+        },
+        eye_left: Cords {
+            x: wave(3.0) * 5.0,
+            y: wave(4.0) * 10.0,
+            z: 0.0,
+        },
+        blend_shapes,
+        received_at: None,
+    }
+}
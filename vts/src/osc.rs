@@ -0,0 +1,277 @@
+use crate::bytes::{read_i32, read_i64, take};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Blob(Vec<u8>),
+    Long(i64),
+    Double(f64),
+    Bool(bool),
+    Nil,
+}
+
+impl OscArg {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            OscArg::Int(v) => Some(*v as f64),
+            OscArg::Float(v) => Some(*v as f64),
+            OscArg::Long(v) => Some(*v as f64),
+            OscArg::Double(v) => Some(*v),
+            OscArg::Bool(v) => Some(if *v { 1.0 } else { 0.0 }),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            OscArg::Str(v) => Some(v.as_str()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub addr: String,
+    pub args: Vec<OscArg>,
+}
+
+/// Decode an OSC packet, flattening bundles into their messages in order
+pub fn decode(buf: &[u8]) -> Result<Vec<OscMessage>, String> {
+    let mut messages = Vec::new();
+    decode_packet(buf, &mut messages)?;
+    Ok(messages)
+}
+
+fn decode_packet(buf: &[u8], messages: &mut Vec<OscMessage>) -> Result<(), String> {
+    if buf.starts_with(b"#bundle\0") {
+        // 8 bytes of "#bundle\0" then an 8 byte time tag, which we ignore
+        let mut pos = 16;
+        while pos < buf.len() {
+            let size = read_i32(buf, &mut pos)?;
+            if size < 0 || pos + size as usize > buf.len() {
+                return Err(format!("Invalid bundle element size: {}", size));
+            }
+            decode_packet(&buf[pos..pos + size as usize], messages)?;
+            pos += size as usize;
+        }
+        return Ok(());
+    }
+
+    let mut pos = 0;
+    let addr = read_string(buf, &mut pos)?;
+    if !addr.starts_with('/') {
+        return Err(format!("Invalid address: {}", addr));
+    }
+
+    let mut args = Vec::new();
+    if pos < buf.len() {
+        let tags = read_string(buf, &mut pos)?;
+        for tag in tags.chars().skip_while(|c| *c == ',') {
+            args.push(match tag {
+                'i' => OscArg::Int(read_i32(buf, &mut pos)?),
+                'f' => OscArg::Float(f32::from_bits(read_i32(buf, &mut pos)? as u32)),
+                's' | 'S' => OscArg::Str(read_string(buf, &mut pos)?),
+                'b' => OscArg::Blob(read_blob(buf, &mut pos)?),
+                'h' | 't' => OscArg::Long(read_i64(buf, &mut pos)?),
+                'd' => OscArg::Double(f64::from_bits(read_i64(buf, &mut pos)? as u64)),
+                'T' => OscArg::Bool(true),
+                'F' => OscArg::Bool(false),
+                'N' | 'I' => OscArg::Nil,
+                _ => return Err(format!("Unsupported type tag: {}", tag)),
+            });
+        }
+    }
+
+    messages.push(OscMessage { addr, args });
+    Ok(())
+}
+
+fn read_string(buf: &[u8], pos: &mut usize) -> Result<String, String> {
+    let rest = &buf[*pos..];
+    let len = match rest.iter().position(|b| *b == 0) {
+        Some(l) => l,
+        None => return Err("Unterminated string".to_string()),
+    };
+    let value = match std::str::from_utf8(&rest[..len]) {
+        Ok(s) => s.to_string(),
+        Err(e) => return Err(e.to_string()),
+    };
+    // string and its null terminator are padded to a multiple of 4 bytes
+    take(buf, pos, (len + 4) & !3)?;
+    Ok(value)
+}
+
+fn read_blob(buf: &[u8], pos: &mut usize) -> Result<Vec<u8>, String> {
+    let len = read_i32(buf, pos)?;
+    if len < 0 {
+        return Err(format!("Invalid blob size: {}", len));
+    }
+    let len = len as usize;
+    let data = take(buf, pos, (len + 3) & !3)?;
+    Ok(data[..len].to_vec())
+}
+
+/// Encode messages as one packet, a bundle with "immediately" time tag if there are several
+pub fn encode(messages: &[OscMessage]) -> Vec<u8> {
+    if let [message] = messages {
+        return encode_message(message);
+    }
+
+    let mut buf = b"#bundle\0".to_vec();
+    buf.extend(1u64.to_be_bytes());
+    for message in messages {
+        let data = encode_message(message);
+        buf.extend((data.len() as i32).to_be_bytes());
+        buf.extend(data);
+    }
+    buf
+}
+
+fn encode_message(message: &OscMessage) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_string(&mut buf, &message.addr);
+
+    let mut tags = String::from(",");
+    let mut data = Vec::new();
+    for arg in &message.args {
+        match arg {
+            OscArg::Int(v) => {
+                tags.push('i');
+                data.extend(v.to_be_bytes());
+            }
+            OscArg::Float(v) => {
+                tags.push('f');
+                data.extend(v.to_be_bytes());
+            }
+            OscArg::Str(v) => {
+                tags.push('s');
+                write_string(&mut data, v);
+            }
+            OscArg::Blob(v) => {
+                tags.push('b');
+                data.extend((v.len() as i32).to_be_bytes());
+                data.extend(v);
+                data.resize((data.len() + 3) & !3, 0);
+            }
+            OscArg::Long(v) => {
+                tags.push('h');
+                data.extend(v.to_be_bytes());
+            }
+            OscArg::Double(v) => {
+                tags.push('d');
+                data.extend(v.to_be_bytes());
+            }
+            OscArg::Bool(v) => tags.push(if *v { 'T' } else { 'F' }),
+            OscArg::Nil => tags.push('N'),
+        }
+    }
+
+    write_string(&mut buf, &tags);
+    buf.extend(data);
+    buf
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    buf.extend(value.as_bytes());
+    // null terminator and padding to a multiple of 4 bytes
+    buf.resize((buf.len() + 4) & !3, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(addr: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage {
+            addr: addr.to_string(),
+            args,
+        }
+    }
+
+    #[test]
+    fn decodes_vmc_blend_value() {
+        let mut packet = b"/VMC/Ext/Blend/Val\0\0,sf\0Joy\0".to_vec();
+        packet.extend(0.5f32.to_be_bytes());
+
+        let messages = decode(&packet).unwrap();
+        assert_eq!(
+            messages,
+            vec![message(
+                "/VMC/Ext/Blend/Val",
+                vec![OscArg::Str("Joy".to_string()), OscArg::Float(0.5)]
+            )]
+        );
+        assert_eq!(encode(&messages), packet);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let messages = vec![message(
+            "/avatar/parameters/JawOpen",
+            vec![
+                OscArg::Int(-7),
+                OscArg::Float(0.25),
+                OscArg::Str("four".to_string()),
+                OscArg::Blob(vec![1, 2, 3, 4, 5]),
+                OscArg::Long(1 << 40),
+                OscArg::Double(-1.5),
+                OscArg::Bool(true),
+                OscArg::Bool(false),
+                OscArg::Nil,
+            ],
+        )];
+
+        let packet = encode(&messages);
+        assert_eq!(packet.len() % 4, 0);
+        assert_eq!(decode(&packet).unwrap(), messages);
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let messages = vec![
+            message(
+                "/VMC/Ext/Blend/Val",
+                vec![OscArg::Str("A".to_string()), OscArg::Float(1.0)],
+            ),
+            message("/VMC/Ext/Blend/Apply", vec![]),
+        ];
+
+        let packet = encode(&messages);
+        assert!(packet.starts_with(b"#bundle\0"));
+        assert_eq!(decode(&packet).unwrap(), messages);
+    }
+
+    #[test]
+    fn nested_bundle_is_flattened() {
+        let inner = encode(&[message("/a", vec![OscArg::Int(1)]), message("/b", vec![])]);
+        let mut packet = b"#bundle\0".to_vec();
+        packet.extend(1u64.to_be_bytes());
+        for element in [inner, encode(&[message("/c", vec![])])] {
+            packet.extend((element.len() as i32).to_be_bytes());
+            packet.extend(element);
+        }
+
+        let addrs: Vec<String> = decode(&packet)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.addr)
+            .collect();
+        assert_eq!(addrs, ["/a", "/b", "/c"]);
+    }
+
+    #[test]
+    fn invalid_packets_are_rejected() {
+        assert!(decode(b"abc\0").is_err());
+        assert!(decode(b"/a\0\0,x\0\0").is_err());
+        assert!(decode(b"/a\0\0,f\0\0\0\0").is_err());
+        assert!(decode(b"/a").is_err());
+
+        let mut packet = b"#bundle\0".to_vec();
+        packet.extend(1u64.to_be_bytes());
+        packet.extend(64i32.to_be_bytes());
+        assert!(decode(&packet).is_err());
+    }
+}
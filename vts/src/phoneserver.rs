@@ -0,0 +1,180 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use log::{error, info, warn};
+
+use crate::{slot::SlotReceiver, transform::Transform, vtsphone::TrackingResponce};
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TrackingRequest {
+    message_type: String,
+    #[serde(default)]
+    sent_by: String,
+    send_for_seconds: u64,
+    ports: Vec<u16>,
+}
+
+struct Client {
+    addr: SocketAddr,
+    until: time::Instant,
+}
+
+/// Phone side of tracking protocol, answers `iOSTrackingDataRequest` and
+/// streams frames to requested ports for requested time
+pub struct PhoneServer {
+    socket: UdpSocket,
+    clients: Vec<Client>,
+}
+
+impl PhoneServer {
+    pub fn bind(port: u16) -> Result<PhoneServer, String> {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).map_err(|e| e.to_string())?;
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Ok(PhoneServer {
+            socket,
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.socket.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    /// Handle received requests and drop expired clients, without blocking
+    pub fn poll_requests(&mut self) {
+        let mut buf = [0; 4096];
+
+        loop {
+            let (amt, src) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                // Windows reports unreachable clients on receive
+                Err(error) if error.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(error) => {
+                    warn!("Unnable to receive tracking request: {}", error);
+                    break;
+                }
+            };
+
+            let request = match serde_json::from_slice::<TrackingRequest>(&buf[..amt]) {
+                Ok(request) if request.message_type == "iOSTrackingDataRequest" => request,
+                Ok(request) => {
+                    warn!("Unknown request: {}", request.message_type);
+                    continue;
+                }
+                Err(error) => {
+                    warn!("Unnable to deserialize request: {}", error);
+                    continue;
+                }
+            };
+
+            let until = time::Instant::now() + time::Duration::from_secs(request.send_for_seconds);
+            for port in request.ports {
+                let addr = SocketAddr::new(src.ip(), port);
+                match self.clients.iter_mut().find(|c| c.addr == addr) {
+                    Some(client) => client.until = until,
+                    None => {
+                        info!("Streaming tracking data to {} ({})", addr, request.sent_by);
+                        self.clients.push(Client { addr, until });
+                    }
+                }
+            }
+        }
+
+        let now = time::Instant::now();
+        self.clients.retain(|client| {
+            let streaming = client.until > now;
+            if !streaming {
+                info!("Stopped streaming to {}", client.addr);
+            }
+            streaming
+        });
+    }
+
+    pub fn has_clients(&self) -> bool {
+        !self.clients.is_empty()
+    }
+
+    /// Send frame to every client that requested data
+    pub fn send(&self, frame: &TrackingResponce) {
+        if self.clients.is_empty() {
+            return;
+        }
+        let data = serde_json::to_vec(frame).unwrap();
+        for client in &self.clients {
+            if let Err(error) = self.socket.send_to(&data, client.addr) {
+                warn!("Unable to send tracking data to {}: {}", client.addr, error);
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EmulatorCfg {
+    /// Port for requests, VTubeStudio sends them to 21412
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// [`Transform`] config applied to frames before sending
+    pub transform: Option<String>,
+}
+
+fn default_port() -> u16 {
+    21412
+}
+
+impl Default for EmulatorCfg {
+    fn default() -> Self {
+        EmulatorCfg {
+            port: default_port(),
+            transform: None,
+        }
+    }
+}
+
+/// Acts as phone for VTubeStudio, so its own iPhone tracking gets the frames
+pub struct PhoneEmulator;
+
+impl PhoneEmulator {
+    pub fn run(
+        port: u16,
+        transform: Option<Transform>,
+        receiver: SlotReceiver<TrackingResponce>,
+        active: Arc<AtomicBool>,
+    ) {
+        let mut server = match PhoneServer::bind(port) {
+            Ok(server) => server,
+            Err(error) => {
+                error!("Unable to emulate phone on port:{}: {}", port, error);
+                return;
+            }
+        };
+        info!("Emulating phone on port:{}", server.port());
+
+        while active.load(Ordering::Relaxed) {
+            server.poll_requests();
+
+            let mut frame = match receiver.try_recv() {
+                Some(frame) => frame,
+                None if receiver.is_connected() => {
+                    thread::sleep(time::Duration::from_millis(1));
+                    continue;
+                }
+                None => return,
+            };
+
+            if let Some(transform) = &transform {
+                transform.apply(&mut frame);
+            }
+            server.send(&frame);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    thread, time,
+};
+
+use evalexpr::Node;
+use log::{error, info, warn};
+
+use crate::{
+    hotkeys::{HotkeyAction, Hotkeys, HotkeysCfg},
+    merge::Merger,
+    sink::{ParamDef, ParamValue, Params, SinkEvent},
+    transform::{context, read_calc_fns},
+    vtsphone::TrackingResponce,
+};
+
+/// Transform functions by param name
+type Funcs = Vec<(String, Node)>;
+
+/// Sinks with the same transform config
+struct Stage {
+    path: String,
+    /// Stage of main transform config, switched by profile hotkeys
+    main: bool,
+    defs: Arc<Vec<ParamDef>>,
+    funcs: Funcs,
+    sinks: Vec<Sender<SinkEvent>>,
+    /// Params that can't be calculated, warned once until they are calculated again
+    failing: HashSet<String>,
+}
+
+/// Transform stage, calculates params of merged frames once for all sinks with the same transform config
+pub struct Pipeline;
+
+impl Pipeline {
+    /// Sinks without own transform config use `transform_cfg_path`
+    pub fn run(
+        mut merger: Merger,
+        transform_cfg_path: String,
+        hotkeys: HotkeysCfg,
+        sinks: Vec<(Option<String>, Sender<SinkEvent>)>,
+        active: Arc<AtomicBool>,
+    ) {
+        let mut hotkeys = Hotkeys::new(hotkeys);
+        let mut stages: Vec<Stage> = Vec::new();
+
+        for (path, sink) in sinks {
+            let main = path.is_none();
+            let path = path.unwrap_or_else(|| transform_cfg_path.clone());
+            if let Some(stage) = stages
+                .iter_mut()
+                .find(|stage| stage.main == main && stage.path == path)
+            {
+                stage.sinks.push(sink);
+                continue;
+            }
+
+            let (defs, funcs) = match Pipeline::load(&path) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    error!("Unable to load transform config: {}", e);
+                    return;
+                }
+            };
+            stages.push(Stage {
+                path,
+                main,
+                defs,
+                funcs,
+                sinks: vec![sink],
+                failing: HashSet::new(),
+            });
+        }
+
+        for stage in &mut stages {
+            let event = SinkEvent::Define(Arc::clone(&stage.defs));
+            Pipeline::broadcast(&mut stage.sinks, event);
+        }
+
+        while active.load(Ordering::Relaxed) && stages.iter().any(|s| !s.sinks.is_empty()) {
+            let mut frame = match merger.poll() {
+                Some(frame) => frame,
+                None => {
+                    thread::sleep(time::Duration::from_millis(1));
+                    continue;
+                }
+            };
+
+            if let Some(action) = hotkeys.pressed(frame.hotkey) {
+                match action {
+                    HotkeyAction::Profile { name } => match hotkeys.profile(&name) {
+                        Some(path) => match Pipeline::load(path) {
+                            Ok((defs, funcs)) => {
+                                for stage in stages.iter_mut().filter(|stage| stage.main) {
+                                    stage.path = path.clone();
+                                    stage.defs = Arc::clone(&defs);
+                                    stage.funcs = funcs.clone();
+                                    stage.failing.clear();
+                                    let event = SinkEvent::Define(Arc::clone(&defs));
+                                    Pipeline::broadcast(&mut stage.sinks, event);
+                                }
+                            }
+                            Err(e) => error!("Unable to load profile {}: {}", name, e),
+                        },
+                        None => warn!("Unknown profile: {}", name),
+                    },
+                    HotkeyAction::Calibrate => hotkeys.calibrate(),
+                    HotkeyAction::VtsHotkey { id } => {
+                        for stage in &mut stages {
+                            Pipeline::broadcast(&mut stage.sinks, SinkEvent::Hotkey(id.clone()));
+                        }
+                    }
+                    HotkeyAction::ToggleGroup { name } => hotkeys.toggle_group(&name),
+                    HotkeyAction::Record { path } => hotkeys.toggle_recording(&path),
+                }
+            }
+
+            hotkeys.frame(&mut frame);
+            for stage in &mut stages {
+                let params = Pipeline::params(&stage.funcs, &frame, &hotkeys, &mut stage.failing);
+                if let Some(params) = params {
+                    Pipeline::broadcast(&mut stage.sinks, SinkEvent::Params(params));
+                }
+            }
+        }
+    }
+
+    /// Send event to every sink, dropping sinks that stopped
+    fn broadcast(sinks: &mut Vec<Sender<SinkEvent>>, event: SinkEvent) {
+        sinks.retain(|sink| sink.send(event.clone()).is_ok());
+    }
+
+    fn load(file_path: &str) -> Result<(Arc<Vec<ParamDef>>, Funcs), String> {
+        info!("Loadling tranformation config: {}", file_path);
+        let mut defs = Vec::new();
+        let mut funcs = Vec::new();
+
+        for func in read_calc_fns(file_path)? {
+            let tree = evalexpr::build_operator_tree(&func.func)
+                .map_err(|e| format!("{}: {}: {}", file_path, func.name, e))?;
+            funcs.push((func.name.clone(), tree));
+            defs.push(ParamDef {
+                name: func.name,
+                min: func.min,
+                max: func.max,
+                default_value: func.default_value,
+            });
+        }
+
+        info!("Tranformation config loaded");
+        Ok((Arc::new(defs), funcs))
+    }
+
+    /// Params of enabled groups, `None` without face
+    fn params(
+        funcs: &[(String, Node)],
+        frame: &TrackingResponce,
+        hotkeys: &Hotkeys,
+        failing: &mut HashSet<String>,
+    ) -> Option<Params> {
+        if !frame.face_found {
+            return None;
+        }
+        let context = context(frame);
+
+        let values: Vec<ParamValue> = funcs
+            .iter()
+            .filter(|(name, _)| hotkeys.is_enabled(name))
+            .filter_map(
+                |(name, tree)| match tree.eval_number_with_context(&context) {
+                    Ok(value) => {
+                        if failing.remove(name) {
+                            info!("Calculating {} again", name);
+                        }
+                        Some(ParamValue {
+                            id: name.clone(),
+                            value: value.clamp(-1000000.0, 1000000.0),
+                        })
+                    }
+                    Err(e) => {
+                        if failing.insert(name.clone()) {
+                            warn!("Unable to calculate {}: {}", name, e);
+                        }
+                        None
+                    }
+                },
+            )
+            .collect();
+
+        if values.is_empty() {
+            return None;
+        }
+        Some(Params {
+            timestamp: frame.timestamp,
+            face_found: frame.face_found,
+            values,
+        })
+    }
+}
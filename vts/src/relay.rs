@@ -0,0 +1,155 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    sink::UdpTarget,
+    slot::{SlotReceiver, SlotSender},
+    transform::Transform,
+    tunnel::Tunnel,
+    vtsphone::TrackingResponce,
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayCfg {
+    /// UDP endpoints (`host:port`) that get every frame in phone format
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// [`Transform`] config applied to frames before sending
+    pub transform: Option<String>,
+    /// Pre-shared key to encrypt frames, relay input must have the same key
+    pub key: Option<String>,
+}
+
+pub struct Relay;
+
+impl Relay {
+    /// Send every frame to all targets, `transform` is loaded from `cfg.transform`
+    pub fn run(
+        cfg: RelayCfg,
+        transform: Option<Transform>,
+        receiver: SlotReceiver<TrackingResponce>,
+        active: Arc<AtomicBool>,
+    ) {
+        info!("Relaying tracking data to {}", cfg.targets.join(", "));
+        let mut targets: Vec<UdpTarget> = cfg.targets.into_iter().map(UdpTarget::new).collect();
+        let mut tunnel = cfg.key.as_deref().map(Tunnel::new);
+        if tunnel.is_some() {
+            info!("Relayed tracking data is encrypted");
+        }
+
+        // Warned once until target accepts data again
+        let mut failing: HashSet<String> = HashSet::new();
+
+        while active.load(Ordering::Relaxed) {
+            let mut frame = match receiver.try_recv() {
+                Some(frame) => frame,
+                None if receiver.is_connected() => {
+                    thread::sleep(time::Duration::from_millis(1));
+                    continue;
+                }
+                None => return,
+            };
+
+            if let Some(transform) = &transform {
+                transform.apply(&mut frame);
+            }
+            let mut data = serde_json::to_vec(&frame).unwrap();
+            if let Some(tunnel) = &mut tunnel {
+                data = tunnel.seal(&data);
+            }
+
+            for target in &mut targets {
+                match target.send(&data) {
+                    Ok(_) => {
+                        if failing.remove(target.target()) {
+                            info!("Relaying to {} again", target.target());
+                        }
+                    }
+                    Err(error) => {
+                        if failing.insert(target.target().to_string()) {
+                            warn!("Unable to relay to {}: {}", target.target(), error);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Input for frames relayed by another rusty-bridge, with key only encrypted frames are accepted
+    pub fn listen(
+        port: u16,
+        key: Option<String>,
+        sender: SlotSender<TrackingResponce>,
+        active: Arc<AtomicBool>,
+    ) {
+        let socket = match UdpSocket::bind(("0.0.0.0", port)) {
+            Ok(socket) => socket,
+            Err(error) => {
+                error!(
+                    "Unable to listen for relayed tracking on port:{}: {}",
+                    port, error
+                );
+                return;
+            }
+        };
+        let _ = socket.set_read_timeout(Some(time::Duration::new(2, 0)));
+        info!("Listening for relayed tracking on port:{}", port);
+
+        let mut tunnel = key.as_deref().map(Tunnel::new);
+        // Warned once, sources of rejected packets
+        let mut rejected: HashSet<IpAddr> = HashSet::new();
+        let mut buf = [0; 65536];
+
+        while active.load(Ordering::Relaxed) {
+            let (amt, src) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(error) => {
+                    warn!("Unnable to receive relayed tracking: {}", error);
+                    continue;
+                }
+            };
+
+            let data = match &mut tunnel {
+                Some(tunnel) => match tunnel.open(&buf[..amt]) {
+                    Ok(data) => data,
+                    Err(error) => {
+                        if rejected.insert(src.ip()) {
+                            warn!("Rejected relayed tracking from {}: {}", src.ip(), error);
+                        }
+                        continue;
+                    }
+                },
+                None => buf[..amt].to_vec(),
+            };
+
+            match serde_json::from_slice::<TrackingResponce>(&data) {
+                Ok(mut frame) => {
+                    frame.received_at = Some(time::Instant::now());
+                    if sender.send(frame).is_err() {
+                        return;
+                    }
+                }
+                Err(error) => {
+                    warn!("Unnable to deserialize: {}", error)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    time,
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    health::VtsMonitor,
+    osc::{self, OscArg, OscMessage},
+    vmc::{VmcHeadCfg, VmcSink},
+    vrchat::{VrChatSink, VrcParamType, DEFAULT_VRCHAT_TARGET},
+    vtspc::{VtsCfg, VtsSink},
+};
+
+/// Param defined by transform config
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ParamDef {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub default_value: f64,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct ParamValue {
+    pub id: String,
+    pub value: f64,
+}
+
+/// Params calculated from one frame
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Params {
+    pub timestamp: u64,
+    pub face_found: bool,
+    pub values: Vec<ParamValue>,
+}
+
+/// Sent by pipeline to every sink
+#[derive(Debug, Clone)]
+pub enum SinkEvent {
+    /// Params of loaded transform config, sent first and after profile change
+    Define(Arc<Vec<ParamDef>>),
+    Params(Params),
+    /// VTubeStudio hotkey by id or name
+    Hotkey(String),
+}
+
+/// Output for calculated params, every sink runs on its own thread
+pub trait TrackingSink: Send {
+    fn name(&self) -> String;
+
+    /// Max params per second when not set in config, `None` for every frame
+    fn default_rate(&self) -> Option<f64> {
+        None
+    }
+
+    fn define(&mut self, _params: &[ParamDef]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn send(&mut self, params: &Params) -> Result<(), String>;
+
+    /// Only VTubeStudio has hotkeys, other sinks ignore them
+    fn trigger_hotkey(&mut self, _id: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// What sink does after failed send
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum OnError {
+    /// Warn once and keep sending, warn again after it recovered
+    #[default]
+    Retry,
+    /// Log error and stop this sink, others keep running
+    Stop,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SinkCfg {
+    /// Own transform config instead of `-t`, not switched by profiles
+    pub transform: Option<String>,
+    /// Max params per second, newer params replace ones not sent yet
+    pub rate: Option<f64>,
+    #[serde(default)]
+    pub on_error: OnError,
+    #[serde(flatten)]
+    pub output: OutputCfg,
+}
+
+impl From<OutputCfg> for SinkCfg {
+    fn from(output: OutputCfg) -> Self {
+        SinkCfg {
+            transform: None,
+            rate: None,
+            on_error: OnError::default(),
+            output,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum OutputCfg {
+    /// VTubeStudio plugin API
+    Vts(VtsCfg),
+    /// Params as JSON lines
+    File { path: String },
+    /// Every param as float to `{prefix}/{name}`
+    Osc {
+        target: String,
+        #[serde(default = "default_osc_prefix")]
+        prefix: String,
+    },
+    /// VMC protocol for 3D avatar apps
+    Vmc {
+        target: String,
+        #[serde(default)]
+        head: VmcHeadCfg,
+    },
+    /// VRChat avatar parameters, float if type is not set
+    #[serde(rename_all = "camelCase")]
+    VrChat {
+        #[serde(default = "default_vrchat_target")]
+        target: String,
+        #[serde(default)]
+        types: HashMap<String, VrcParamType>,
+    },
+    /// Params in log
+    Debug,
+}
+
+fn default_vrchat_target() -> String {
+    DEFAULT_VRCHAT_TARGET.to_string()
+}
+
+/// Address prefix of OSC sink when not set
+pub const DEFAULT_OSC_PREFIX: &str = "/rusty-bridge";
+
+fn default_osc_prefix() -> String {
+    DEFAULT_OSC_PREFIX.to_string()
+}
+
+impl OutputCfg {
+    /// State of VTubeStudio sink is published to monitor added to `monitors` with sink name
+    pub fn build(
+        self,
+        monitors: &mut Vec<(String, VtsMonitor)>,
+    ) -> Result<Box<dyn TrackingSink>, String> {
+        Ok(match self {
+            OutputCfg::Vts(cfg) => {
+                let monitor = VtsMonitor::default();
+                let sink = VtsSink::new(cfg, monitor.clone())?;
+                monitors.push((sink.name(), monitor));
+                Box::new(sink)
+            }
+            OutputCfg::File { path } => Box::new(FileSink::create(&path)?),
+            OutputCfg::Osc { target, prefix } => Box::new(OscSink::new(target, prefix)),
+            OutputCfg::Vmc { target, head } => Box::new(VmcSink::new(target, head)),
+            OutputCfg::VrChat { target, types } => Box::new(VrChatSink::new(target, types)),
+            OutputCfg::Debug => Box::new(DebugSink),
+        })
+    }
+}
+
+/// Deliver events to sink, limited to `rate` params per second
+///
+/// Params are dropped when sink is slower than pipeline, definitions and hotkeys never are
+pub fn run_sink(
+    mut sink: Box<dyn TrackingSink>,
+    cfg_rate: Option<f64>,
+    on_error: OnError,
+    receiver: Receiver<SinkEvent>,
+    active: Arc<AtomicBool>,
+) {
+    let name = sink.name();
+    let interval = cfg_rate
+        .or(sink.default_rate())
+        .filter(|rate| *rate > 0.0)
+        .map(|rate| time::Duration::from_secs_f64(1.0 / rate));
+    info!("Sending params to {}", name);
+
+    let mut next_time = time::Instant::now();
+    let mut latest: Option<Params> = None;
+    let mut failing = false;
+
+    while active.load(Ordering::Relaxed) {
+        let timeout = match (&latest, interval) {
+            (Some(_), Some(_)) => next_time.saturating_duration_since(time::Instant::now()),
+            (Some(_), None) => time::Duration::ZERO,
+            (None, _) => time::Duration::from_millis(100),
+        };
+
+        let result = match receiver.recv_timeout(timeout) {
+            Ok(SinkEvent::Params(params)) => {
+                latest = Some(params);
+                continue;
+            }
+            Ok(SinkEvent::Define(params)) => sink.define(&params),
+            Ok(SinkEvent::Hotkey(id)) => sink.trigger_hotkey(&id),
+            Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => match latest.take() {
+                Some(params) if next_time <= time::Instant::now() => {
+                    if let Some(interval) = interval {
+                        next_time = (next_time + interval).max(time::Instant::now());
+                    }
+                    sink.send(&params)
+                }
+                params => {
+                    latest = params;
+                    continue;
+                }
+            },
+        };
+
+        match result {
+            Ok(_) => {
+                if failing {
+                    failing = false;
+                    info!("Sending params to {} again", name);
+                }
+            }
+            Err(e) if on_error == OnError::Stop => {
+                error!("Stopped sending params to {}: {}", name, e);
+                return;
+            }
+            Err(e) => {
+                if !failing {
+                    failing = true;
+                    warn!("Unable to send params to {}: {}", name, e);
+                }
+            }
+        }
+    }
+}
+
+pub struct FileSink {
+    path: String,
+    file: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn create(path: &str) -> Result<FileSink, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(FileSink {
+            path: path.to_string(),
+            file: BufWriter::new(file),
+        })
+    }
+}
+
+impl TrackingSink for FileSink {
+    fn name(&self) -> String {
+        format!("file {}", self.path)
+    }
+
+    fn send(&mut self, params: &Params) -> Result<(), String> {
+        serde_json::to_writer(&mut self.file, params).map_err(|e| e.to_string())?;
+        self.file.write_all(b"\n").map_err(|e| e.to_string())
+    }
+}
+
+/// Target is resolved again after this, so hostname can change address
+const RESOLVE_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// UDP endpoint (`host:port`) of a sink or relay, socket is bound for address family of target
+pub(crate) struct UdpTarget {
+    target: String,
+    resolved: Option<(UdpSocket, SocketAddr)>,
+    /// Failed resolving waits for it too, so unknown host isn't looked up for every frame
+    resolve_at: time::Instant,
+}
+
+impl UdpTarget {
+    pub fn new(target: String) -> UdpTarget {
+        UdpTarget {
+            target,
+            resolved: None,
+            resolve_at: time::Instant::now(),
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Until resolving succeeds again, data is sent to the last address
+    pub fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.resolve_at <= time::Instant::now() {
+            self.resolve_at = time::Instant::now() + RESOLVE_INTERVAL;
+            let resolved = self.resolve();
+            if self.resolved.is_none() {
+                resolved?;
+            }
+        }
+
+        let (socket, addr) = self
+            .resolved
+            .as_ref()
+            .ok_or_else(|| format!("No address for {}", self.target))?;
+        socket.send_to(data, addr).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn resolve(&mut self) -> Result<(), String> {
+        let addr = self
+            .target
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| format!("No address for {}", self.target))?;
+        let socket = match self.resolved.take() {
+            Some((socket, old)) if old.is_ipv4() == addr.is_ipv4() => Ok(socket),
+            _ if addr.is_ipv4() => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)),
+            _ => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)),
+        }
+        .map_err(|e| e.to_string())?;
+        self.resolved = Some((socket, addr));
+        Ok(())
+    }
+}
+
+pub struct OscSink {
+    target: UdpTarget,
+    prefix: String,
+}
+
+impl OscSink {
+    pub fn new(target: String, prefix: String) -> OscSink {
+        OscSink {
+            target: UdpTarget::new(target),
+            prefix: prefix.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl TrackingSink for OscSink {
+    fn name(&self) -> String {
+        format!("OSC {}", self.target.target())
+    }
+
+    fn send(&mut self, params: &Params) -> Result<(), String> {
+        let messages: Vec<OscMessage> = params
+            .values
+            .iter()
+            .map(|param| OscMessage {
+                addr: format!("{}/{}", self.prefix, param.id),
+                args: vec![OscArg::Float(param.value as f32)],
+            })
+            .collect();
+        if messages.is_empty() {
+            return Ok(());
+        }
+        self.target.send(&osc::encode(&messages))
+    }
+}
+
+pub struct DebugSink;
+
+impl TrackingSink for DebugSink {
+    fn name(&self) -> String {
+        "debug".to_string()
+    }
+
+    fn default_rate(&self) -> Option<f64> {
+        Some(1.0)
+    }
+
+    fn define(&mut self, params: &[ParamDef]) -> Result<(), String> {
+        let names: Vec<&str> = params.iter().map(|p| p.name.as_str()).collect();
+        info!("Params: {}", names.join(", "));
+        Ok(())
+    }
+
+    fn send(&mut self, params: &Params) -> Result<(), String> {
+        let values: Vec<String> = params
+            .values
+            .iter()
+            .map(|p| format!("{}={:.3}", p.id, p.value))
+            .collect();
+        info!("{} {}", params.timestamp, values.join(" "));
+        Ok(())
+    }
+
+    fn trigger_hotkey(&mut self, id: &str) -> Result<(), String> {
+        info!("VTubeStudio hotkey: {}", id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sink keys of several VTubeStudio instances keep their meaning as VtsCfg grows
+    #[test]
+    fn vts_sink_cfg() {
+        let cfg: SinkCfg = serde_json::from_str(
+            r#"{ "kind": "vts", "port": 8002, "tokenFile": "token-chibi", "transform": "chibi.json" }"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.transform.as_deref(), Some("chibi.json"));
+        let OutputCfg::Vts(vts) = cfg.output else {
+            panic!("not a vts sink: {:?}", cfg.output);
+        };
+        assert_eq!(vts.port, Some(8002));
+        assert_eq!(vts.token_file, "token-chibi");
+        assert_eq!(vts.host, "localhost");
+
+        let cfg: SinkCfg = serde_json::from_str(r#"{ "kind": "vts" }"#).unwrap();
+        let OutputCfg::Vts(vts) = cfg.output else {
+            panic!("not a vts sink: {:?}", cfg.output);
+        };
+        assert_eq!(vts.port, None);
+        assert_eq!(vts.token_file, "token");
+    }
+}
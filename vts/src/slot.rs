@@ -0,0 +1,46 @@
+use std::sync::{Arc, Mutex};
+
+/// Latest-value handoff between two threads
+///
+/// Sending replaces the value that was not received yet, so nothing is queued
+/// and a slow receiver always gets the newest value
+pub fn slot<T>() -> (SlotSender<T>, SlotReceiver<T>) {
+    let inner = Arc::new(Mutex::new(None));
+    (
+        SlotSender {
+            inner: Arc::clone(&inner),
+        },
+        SlotReceiver { inner },
+    )
+}
+
+pub struct SlotSender<T> {
+    inner: Arc<Mutex<Option<T>>>,
+}
+
+pub struct SlotReceiver<T> {
+    inner: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> SlotSender<T> {
+    /// Fails with the value when receiver is gone
+    pub fn send(&self, value: T) -> Result<(), T> {
+        if Arc::strong_count(&self.inner) < 2 {
+            return Err(value);
+        }
+        *self.inner.lock().unwrap() = Some(value);
+        Ok(())
+    }
+}
+
+impl<T> SlotReceiver<T> {
+    /// Take the latest value, if there is a new one
+    pub fn try_recv(&self) -> Option<T> {
+        self.inner.lock().unwrap().take()
+    }
+
+    /// False when sender is gone
+    pub fn is_connected(&self) -> bool {
+        Arc::strong_count(&self.inner) > 1
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs;
+
+use evalexpr::{ContextWithMutableVariables, HashMapContext, Node};
+use log::warn;
+
+use crate::vtsphone::TrackingResponce;
+
+/// Head cords set for transform config in addition to blend shapes
+pub const HEAD_VARIABLES: [&str; 6] = [
+    "HeadPosX", "HeadPosY", "HeadPosZ", "HeadRotX", "HeadRotY", "HeadRotZ",
+];
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CalcFn {
+    pub name: String,
+    pub func: String,
+    pub min: f64,
+    pub max: f64,
+    pub default_value: f64,
+}
+
+pub(crate) fn read_calc_fns(file_path: &str) -> Result<Vec<CalcFn>, String> {
+    let config = fs::read_to_string(file_path).map_err(|e| format!("{}: {}", file_path, e))?;
+    serde_json::from_str(&config).map_err(|e| format!("{}: {}", file_path, e))
+}
+
+/// Variables of a frame for transform functions
+pub(crate) fn context(frame: &TrackingResponce) -> HashMapContext {
+    let mut context = HashMapContext::new();
+
+    for (k, v) in frame.blend_shapes.iter() {
+        context.set_value(k.to_string(), v.into()).unwrap();
+    }
+
+    let cords = [
+        frame.position.x,
+        frame.position.y,
+        frame.position.z,
+        frame.rotation.x,
+        frame.rotation.y,
+        frame.rotation.z,
+    ];
+    for (k, v) in HEAD_VARIABLES.iter().zip(cords) {
+        context.set_value(k.to_string(), v.into()).unwrap();
+    }
+
+    context
+}
+
+/// Transform config applied to frames themselves, for outputs in phone format
+///
+/// Results named as head cords or blend shapes replace them, other results are added as custom blend shapes
+pub struct Transform {
+    funcs: Vec<(String, Node)>,
+}
+
+impl Transform {
+    pub fn load(file_path: &str) -> Result<Transform, String> {
+        let funcs = read_calc_fns(file_path)?
+            .into_iter()
+            .map(|func| match evalexpr::build_operator_tree(&func.func) {
+                Ok(tree) => Ok((func.name, tree)),
+                Err(e) => Err(format!("{}: {}: {}", file_path, func.name, e)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Transform { funcs })
+    }
+
+    pub fn apply(&self, frame: &mut TrackingResponce) {
+        let context = context(frame);
+
+        for (name, tree) in &self.funcs {
+            let v = match tree.eval_number_with_context(&context) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Unable to calculate {}: {}", name, e);
+                    continue;
+                }
+            };
+
+            match name.as_str() {
+                "HeadPosX" => frame.position.x = v,
+                "HeadPosY" => frame.position.y = v,
+                "HeadPosZ" => frame.position.z = v,
+                "HeadRotX" => frame.rotation.x = v,
+                "HeadRotY" => frame.rotation.y = v,
+                "HeadRotZ" => frame.rotation.z = v,
+                _ => frame.blend_shapes.insert(name, v),
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use std::collections::HashMap;
+
+use crate::vtsphone::now_millis;
+
+/// Bound to every packet, so packets of other protocols with the same key are rejected
+const AAD: &[u8] = b"rusty-bridge relay 1";
+/// Fixed for the protocol, both sides must derive the same key from passphrase
+const KDF_SALT: &[u8] = b"rusty-bridge relay key 1";
+const KDF_ROUNDS: u32 = 200_000;
+/// Counters of senders seen most recently, a restarted sender gets new id
+const MAX_SENDERS: usize = 64;
+
+/// Authenticated encryption of relayed frames with pre-shared key
+///
+/// Packet is 12 bytes nonce (8 bytes counter, 4 bytes random sender id) and ChaCha20-Poly1305 ciphertext.
+/// Counter starts at current time in microseconds and grows with every packet,
+/// packets with counter not bigger than last received one from the same sender are rejected as replayed.
+/// Nonce is authenticated, so sender id can't be changed without the key
+#[derive(Clone)]
+pub struct Tunnel {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    sender: [u8; 4],
+    last: HashMap<[u8; 4], u64>,
+    /// Highest counter of senders dropped from `last`, unknown senders must be newer
+    forgotten: u64,
+}
+
+impl Tunnel {
+    /// Key is any passphrase, both sides must use the same.
+    /// Cipher key is derived with PBKDF2-HMAC-SHA256, so it takes a moment
+    pub fn new(key: &str) -> Tunnel {
+        let mut derived = [0; 32];
+        pbkdf2_hmac::<Sha256>(key.as_bytes(), KDF_SALT, KDF_ROUNDS, &mut derived);
+        let mut sender = [0; 4];
+        OsRng.fill_bytes(&mut sender);
+
+        Tunnel {
+            cipher: ChaCha20Poly1305::new(&derived.into()),
+            counter: now_millis() * 1000,
+            sender,
+            last: HashMap::new(),
+            forgotten: 0,
+        }
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        self.counter += 1;
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[8..].copy_from_slice(&self.sender);
+
+        let payload = Payload {
+            msg: data,
+            aad: AAD,
+        };
+        // Fails only for messages bigger than 256 GiB
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .unwrap();
+
+        let mut packet = nonce.to_vec();
+        packet.extend(ciphertext);
+        packet
+    }
+
+    /// Each sender has own counter, so senders sharing key or with different clocks don't reject each other
+    pub fn open(&mut self, packet: &[u8]) -> Result<Vec<u8>, String> {
+        if packet.len() < 12 {
+            return Err("Packet is too short".to_string());
+        }
+        let (nonce, ciphertext) = packet.split_at(12);
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: AAD,
+        };
+        let data = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| "Packet is not authentic (wrong key?)".to_string())?;
+
+        let counter = u64::from_be_bytes(nonce[..8].try_into().unwrap());
+        let sender: [u8; 4] = nonce[8..].try_into().unwrap();
+        let last = self.last.get(&sender).copied().unwrap_or(self.forgotten);
+        if counter <= last {
+            return Err("Packet is replayed".to_string());
+        }
+
+        if !self.last.contains_key(&sender) && self.last.len() >= MAX_SENDERS {
+            // counters are times, so the smallest one is of the longest silent sender
+            if let Some((&oldest, &counter)) = self.last.iter().min_by_key(|(_, counter)| **counter)
+            {
+                self.last.remove(&oldest);
+                self.forgotten = self.forgotten.max(counter);
+            }
+        }
+        self.last.insert(sender, counter);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    /// Key derivation is slow, so it runs once per key
+    fn tunnel(key: &str) -> Tunnel {
+        static SECRET: OnceLock<Tunnel> = OnceLock::new();
+        static OTHER: OnceLock<Tunnel> = OnceLock::new();
+        let cached = match key {
+            "secret" => &SECRET,
+            _ => &OTHER,
+        };
+        cached.get_or_init(|| Tunnel::new(key)).clone()
+    }
+
+    /// Sender with own id, like another rusty-bridge with the same key
+    fn sender(id: u32) -> Tunnel {
+        let mut sender = tunnel("secret");
+        sender.sender = id.to_be_bytes();
+        sender
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut sender = tunnel("secret");
+        let mut receiver = tunnel("secret");
+
+        let packet = sender.seal(b"frame");
+        assert_ne!(&packet[12..], b"frame");
+        assert_eq!(receiver.open(&packet).unwrap(), b"frame");
+    }
+
+    #[test]
+    fn tampered_is_rejected() {
+        let mut sender = tunnel("secret");
+        let mut receiver = tunnel("secret");
+
+        let mut packet = sender.seal(b"frame");
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        assert!(receiver.open(&packet).is_err());
+
+        // sender id is authenticated too
+        let mut packet = sender.seal(b"frame");
+        packet[8] ^= 1;
+        assert!(receiver.open(&packet).is_err());
+
+        assert!(receiver.open(&[0; 11]).is_err());
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let mut sender = tunnel("secret");
+        let mut receiver = tunnel("other");
+
+        let packet = sender.seal(b"frame");
+        assert!(receiver.open(&packet).is_err());
+    }
+
+    #[test]
+    fn replay_is_rejected() {
+        let mut sender = tunnel("secret");
+        let mut receiver = tunnel("secret");
+
+        let first = sender.seal(b"first");
+        let second = sender.seal(b"second");
+        assert!(receiver.open(&second).is_ok());
+        assert!(receiver.open(&second).is_err());
+        assert!(receiver.open(&first).is_err());
+    }
+
+    #[test]
+    fn senders_are_independent() {
+        let mut ahead = sender(1);
+        let mut behind = sender(2);
+        let mut receiver = tunnel("secret");
+        behind.counter = ahead.counter - 1_000_000;
+
+        assert!(receiver.open(&ahead.seal(b"ahead")).is_ok());
+        assert!(receiver.open(&behind.seal(b"behind")).is_ok());
+        assert!(receiver.open(&behind.seal(b"behind")).is_ok());
+        assert!(receiver.open(&ahead.seal(b"ahead")).is_ok());
+    }
+
+    #[test]
+    fn forgotten_sender_is_not_replayed() {
+        let mut receiver = tunnel("secret");
+        let mut first = sender(0);
+        let replayed = first.seal(b"first");
+        assert!(receiver.open(&replayed).is_ok());
+
+        for id in 1..=MAX_SENDERS as u32 {
+            assert!(receiver.open(&sender(id).seal(b"frame")).is_ok());
+        }
+        assert_eq!(receiver.last.len(), MAX_SENDERS);
+        assert!(receiver.open(&replayed).is_err());
+        assert_eq!(receiver.last.len(), MAX_SENDERS);
+    }
+}
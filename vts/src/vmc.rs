@@ -0,0 +1,283 @@
+use std::{
+    io,
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time,
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    osc::{self, OscArg, OscMessage},
+    sink::{Params, TrackingSink, UdpTarget},
+    slot::SlotSender,
+    vtsphone::{now_millis, BlendShapes, Cords, TrackingResponce},
+};
+
+/// Receiver for the VMC protocol (OSC over UDP)
+///
+/// Blend shapes from `/VMC/Ext/Blend/Val` are collected until `/VMC/Ext/Blend/Apply`,
+/// then sent as one frame together with the last `Head` and `LeftEye` bones
+/// from `/VMC/Ext/Bone/Pos`
+pub struct Vmc;
+
+#[derive(Default)]
+struct VmcState {
+    blend_shapes: BlendShapes,
+    rotation: Cords,
+    position: Cords,
+    eye_left: Cords,
+    face_found: bool,
+}
+
+impl Vmc {
+    pub fn run(port: u16, sender: SlotSender<TrackingResponce>, active: Arc<AtomicBool>) {
+        let socket = match UdpSocket::bind(("0.0.0.0", port)) {
+            Ok(socket) => socket,
+            Err(error) => {
+                error!("Unable to listen for VMC on port:{}: {}", port, error);
+                return;
+            }
+        };
+        let _ = socket.set_read_timeout(Some(time::Duration::new(2, 0)));
+        info!("Listening for VMC on port:{}", port);
+
+        let mut buf = [0; 65536];
+        let mut state = VmcState {
+            face_found: true,
+            ..Default::default()
+        };
+
+        while active.load(Ordering::Relaxed) {
+            match socket.recv_from(&mut buf) {
+                Ok((amt, _src)) => match osc::decode(&buf[..amt]) {
+                    Ok(messages) => {
+                        for msg in messages {
+                            if let Some(frame) = state.apply(msg) {
+                                if sender.send(frame).is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                    Err(error) => {
+                        warn!("Unnable to decode VMC packet: {}", error)
+                    }
+                },
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut => {}
+                Err(error) => {
+                    warn!("Unnable to receive VMC: {}", error)
+                }
+            }
+        }
+    }
+}
+
+impl VmcState {
+    fn apply(&mut self, msg: OscMessage) -> Option<TrackingResponce> {
+        match msg.addr.as_str() {
+            "/VMC/Ext/Blend/Val" => {
+                if let (Some(name), Some(value)) = (
+                    msg.args.first().and_then(OscArg::as_str),
+                    msg.args.get(1).and_then(OscArg::as_f64),
+                ) {
+                    self.blend_shapes.insert(name, value);
+                }
+                None
+            }
+            "/VMC/Ext/Bone/Pos" => {
+                let name = msg.args.first().and_then(OscArg::as_str)?;
+                let v: Vec<f64> = msg.args[1..].iter().filter_map(OscArg::as_f64).collect();
+                if v.len() < 7 {
+                    return None;
+                }
+                match name {
+                    "Head" => {
+                        // meters to centimeters, close to what the phone sends
+                        self.position = Cords {
+                            x: v[0] * 100.0,
+                            y: v[1] * 100.0,
+                            z: v[2] * 100.0,
+                        };
+                        self.rotation = quat_to_euler(v[3], v[4], v[5], v[6]);
+                    }
+                    "LeftEye" => {
+                        self.eye_left = quat_to_euler(v[3], v[4], v[5], v[6]);
+                    }
+                    _ => {}
+                }
+                None
+            }
+            "/VMC/Ext/OK" => {
+                // Since VMC 2.7 the fourth argument is the tracking status
+                if let Some(status) = msg.args.get(3).and_then(OscArg::as_f64) {
+                    self.face_found = status != 0.0;
+                }
+                None
+            }
+            "/VMC/Ext/Blend/Apply" => Some(self.frame()),
+            _ => None,
+        }
+    }
+
+    fn frame(&self) -> TrackingResponce {
+        TrackingResponce {
+            timestamp: now_millis(),
+            hotkey: -1,
+            face_found: self.face_found,
+            rotation: self.rotation.clone(),
+            position: self.position.clone(),
+            eye_left: self.eye_left.clone(),
+            blend_shapes: self.blend_shapes.clone(),
+            received_at: Some(time::Instant::now()),
+        }
+    }
+}
+
+/// Unity quaternion to euler angles in degrees (Z, then X, then Y)
+fn quat_to_euler(x: f64, y: f64, z: f64, w: f64) -> Cords {
+    let pitch = (2.0 * (w * x - y * z)).clamp(-1.0, 1.0).asin();
+    let yaw = (2.0 * (w * y + x * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+    let roll = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (x * x + z * z));
+
+    Cords {
+        x: pitch.to_degrees(),
+        y: yaw.to_degrees(),
+        z: roll.to_degrees(),
+    }
+}
+
+/// Unity rotation (Z, then X, then Y) of euler angles in degrees to quaternion
+fn euler_to_quat(euler: &Cords) -> [f64; 4] {
+    let half = |angle: f64| angle.to_radians() / 2.0;
+    let (sx, cx) = half(euler.x).sin_cos();
+    let (sy, cy) = half(euler.y).sin_cos();
+    let (sz, cz) = half(euler.z).sin_cos();
+
+    [
+        cy * sx * cz + sy * cx * sz,
+        sy * cx * cz - cy * sx * sz,
+        cy * cx * sz - sy * sx * cz,
+        cy * cx * cz + sy * sx * sz,
+    ]
+}
+
+/// Params with head rotation in degrees, sent as `Head` bone instead of blend shapes
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VmcHeadCfg {
+    #[serde(default = "default_pitch")]
+    pub pitch: String,
+    #[serde(default = "default_yaw")]
+    pub yaw: String,
+    #[serde(default = "default_roll")]
+    pub roll: String,
+}
+
+fn default_pitch() -> String {
+    "FaceAngleY".to_string()
+}
+
+fn default_yaw() -> String {
+    "FaceAngleX".to_string()
+}
+
+fn default_roll() -> String {
+    "FaceAngleZ".to_string()
+}
+
+impl Default for VmcHeadCfg {
+    fn default() -> Self {
+        VmcHeadCfg {
+            pitch: default_pitch(),
+            yaw: default_yaw(),
+            roll: default_roll(),
+        }
+    }
+}
+
+/// Sender for the VMC protocol, for 3D avatar apps (VSeeFace, Warudo, ...)
+///
+/// Every params are sent as one bundle: `Head` bone from head params,
+/// other params as `/VMC/Ext/Blend/Val` and then `/VMC/Ext/Blend/Apply`
+pub struct VmcSink {
+    target: UdpTarget,
+    head: VmcHeadCfg,
+    started: time::Instant,
+}
+
+impl VmcSink {
+    pub fn new(target: String, head: VmcHeadCfg) -> VmcSink {
+        VmcSink {
+            target: UdpTarget::new(target),
+            head,
+            started: time::Instant::now(),
+        }
+    }
+
+    fn messages(&self, params: &Params) -> Vec<OscMessage> {
+        let mut rotation = Cords::default();
+        let mut messages = vec![
+            OscMessage {
+                addr: "/VMC/Ext/OK".to_string(),
+                args: vec![OscArg::Int(1)],
+            },
+            OscMessage {
+                addr: "/VMC/Ext/T".to_string(),
+                args: vec![OscArg::Float(self.started.elapsed().as_secs_f32())],
+            },
+        ];
+
+        for param in &params.values {
+            let id = param.id.as_str();
+            if id == self.head.pitch {
+                rotation.x = param.value;
+            } else if id == self.head.yaw {
+                rotation.y = param.value;
+            } else if id == self.head.roll {
+                rotation.z = param.value;
+            } else {
+                messages.push(OscMessage {
+                    addr: "/VMC/Ext/Blend/Val".to_string(),
+                    args: vec![
+                        OscArg::Str(param.id.clone()),
+                        OscArg::Float(param.value as f32),
+                    ],
+                });
+            }
+        }
+
+        let [qx, qy, qz, qw] = euler_to_quat(&rotation);
+        let mut bone = vec![OscArg::Str("Head".to_string())];
+        bone.extend([0.0, 0.0, 0.0, qx, qy, qz, qw].map(|v| OscArg::Float(v as f32)));
+        messages.insert(
+            2,
+            OscMessage {
+                addr: "/VMC/Ext/Bone/Pos".to_string(),
+                args: bone,
+            },
+        );
+
+        messages.push(OscMessage {
+            addr: "/VMC/Ext/Blend/Apply".to_string(),
+            args: Vec::new(),
+        });
+        messages
+    }
+}
+
+impl TrackingSink for VmcSink {
+    fn name(&self) -> String {
+        format!("VMC {}", self.target.target())
+    }
+
+    fn send(&mut self, params: &Params) -> Result<(), String> {
+        let messages = self.messages(params);
+        self.target.send(&osc::encode(&messages))
+    }
+}
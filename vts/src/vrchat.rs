@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use crate::{
+    osc::{self, OscArg, OscMessage},
+    sink::{ParamDef, Params, TrackingSink, UdpTarget},
+};
+
+/// VRChat listens for OSC on this port
+pub const DEFAULT_VRCHAT_TARGET: &str = "127.0.0.1:9000";
+
+/// Float changes smaller than this are not sent, VRChat syncs floats with 8 bits anyway
+const FLOAT_THRESHOLD: f64 = 0.001;
+
+/// Type of avatar parameter
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum VrcParamType {
+    /// Clamped to -1...1
+    #[default]
+    Float,
+    /// Rounded and clamped to 0...255
+    Int,
+    /// True from 0.5
+    Bool,
+}
+
+impl VrcParamType {
+    fn arg(self, value: f64) -> OscArg {
+        match self {
+            VrcParamType::Float => OscArg::Float(value.clamp(-1.0, 1.0) as f32),
+            VrcParamType::Int => OscArg::Int(value.round().clamp(0.0, 255.0) as i32),
+            VrcParamType::Bool => OscArg::Bool(value >= 0.5),
+        }
+    }
+}
+
+/// VRChat avatar parameters, `/avatar/parameters/<name>` for every param when it changes
+pub struct VrChatSink {
+    target: UdpTarget,
+    types: HashMap<String, VrcParamType>,
+    /// Last sent value by param
+    sent: HashMap<String, OscArg>,
+}
+
+impl VrChatSink {
+    pub fn new(target: String, types: HashMap<String, VrcParamType>) -> VrChatSink {
+        VrChatSink {
+            target: UdpTarget::new(target),
+            types,
+            sent: HashMap::new(),
+        }
+    }
+
+    fn changed(&self, id: &str, arg: &OscArg) -> bool {
+        match (self.sent.get(id), arg) {
+            (Some(OscArg::Float(old)), OscArg::Float(new)) => {
+                (*new as f64 - *old as f64).abs() >= FLOAT_THRESHOLD
+            }
+            (Some(old), new) => old != new,
+            (None, _) => true,
+        }
+    }
+}
+
+impl TrackingSink for VrChatSink {
+    fn name(&self) -> String {
+        format!("VRChat {}", self.target.target())
+    }
+
+    /// Avatar gets every param again after profile change
+    fn define(&mut self, _params: &[ParamDef]) -> Result<(), String> {
+        self.sent.clear();
+        Ok(())
+    }
+
+    fn send(&mut self, params: &Params) -> Result<(), String> {
+        for param in &params.values {
+            let param_type = self.types.get(&param.id).copied().unwrap_or_default();
+            let arg = param_type.arg(param.value);
+            if !self.changed(&param.id, &arg) {
+                continue;
+            }
+
+            let message = OscMessage {
+                addr: format!("/avatar/parameters/{}", param.id),
+                args: vec![arg.clone()],
+            };
+            self.target.send(&osc::encode(&[message]))?;
+            self.sent.insert(param.id.clone(), arg);
+        }
+        Ok(())
+    }
+}
@@ -1,20 +1,27 @@
 use std::{
-    collections::VecDeque,
-    fs,
-    net::{TcpStream, UdpSocket},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::Receiver,
-        Arc,
-    },
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{atomic::AtomicBool, mpsc, Arc},
+    thread, time,
 };
 
-use evalexpr::{ContextWithMutableVariables, HashMapContext, Node};
 use log::{error, info, warn};
+use native_tls::{Certificate, TlsConnector};
 use serde_json::Value;
-use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
-
-use crate::vtsphone::TrackingResponce;
+use socket2::{Domain, Protocol, Socket, Type};
+use tungstenite::{stream::MaybeTlsStream, Connector, Message, WebSocket};
+
+use crate::{
+    health::{VtsMonitor, VtsState},
+    hotkeys::HotkeysCfg,
+    merge::Merger,
+    pipeline::Pipeline,
+    sink::{run_sink, OnError, ParamDef, ParamValue, Params, TrackingSink},
+    transform::{read_calc_fns, HEAD_VARIABLES},
+    vtsphone::{unknown_name, BlendShape},
+};
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -33,14 +40,14 @@ struct VTSApiResponce<T> {
 struct VTSApiRequest<'a, T> {
     api_name: &'a str,
     api_version: &'a str,
-    #[serde(rename(deserialize = "requestID"))]
+    #[serde(rename = "requestID")]
     request_id: &'a str,
     message_type: &'a str,
     data: Option<T>,
 }
 
 pub mod responces {
-    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
     #[serde(rename_all = "camelCase")]
     pub struct Discovery {
         pub active: bool,
@@ -114,6 +121,12 @@ pub mod requests {
         pub value: f64, // -1000000 | 1000000
     }
 
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    pub struct HotkeyTrigger<'a> {
+        #[serde(rename = "hotkeyID")]
+        pub hotkey_id: &'a str,
+    }
+
     #[derive(serde::Serialize, serde::Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
     pub struct InjectParams<'a> {
@@ -123,358 +136,874 @@ pub mod requests {
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct CalcFn {
-    name: String,
-    func: String,
-    min: f64,
-    max: f64,
-    default_value: f64,
-}
-
 pub struct VtsPc;
 
 impl VtsPc {
+    /// Check that transform config parses and uses only known variables
+    ///
+    /// `variables` are custom names sent by inputs, in addition to head cords and ARKit blend shapes
+    pub fn check_transform_cfg(file_path: &str, variables: &[String]) -> Result<(), String> {
+        let calc_fns = read_calc_fns(file_path)?;
+
+        let known = || {
+            HEAD_VARIABLES
+                .iter()
+                .copied()
+                .chain(BlendShape::ALL.iter().map(|shape| shape.name()))
+                .chain(variables.iter().map(String::as_str))
+        };
+
+        let mut errors = Vec::new();
+        for func in &calc_fns {
+            let tree = match evalexpr::build_operator_tree(&func.func) {
+                Ok(tree) => tree,
+                Err(e) => {
+                    errors.push(format!("{}: {}", func.name, e));
+                    continue;
+                }
+            };
+            let used: BTreeSet<&str> = tree.iter_read_variable_identifiers().collect();
+            for variable in used {
+                if !known().any(|k| k == variable) {
+                    let error = unknown_name("variable", variable, known());
+                    errors.push(format!("{}: {}", func.name, error));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(format!("{}:\n{}", file_path, errors.join("\n")))
+    }
+
+    /// Send params only to local VTubeStudio, its state is published to `monitor`
     pub fn run(
-        receiver: Receiver<TrackingResponce>,
+        merger: Merger,
         transformation_cfg_path: String,
+        hotkeys: HotkeysCfg,
+        monitor: VtsMonitor,
         active: Arc<AtomicBool>,
     ) {
-        while active.load(Ordering::Relaxed) {
-            let flag = Arc::clone(&active);
+        let vts = match VtsSink::new(VtsCfg::default(), monitor) {
+            Ok(vts) => vts,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
 
-            let websocket = VtsPc::connect();
-            VtsPc::msg_loop(websocket, &receiver, &transformation_cfg_path, flag);
-        }
+        let (sender, receiver) = mpsc::channel();
+        let flag = Arc::clone(&active);
+        let sink =
+            thread::spawn(move || run_sink(Box::new(vts), None, OnError::Retry, receiver, flag));
+
+        let sinks = vec![(None, sender)];
+        Pipeline::run(merger, transformation_cfg_path, hotkeys, sinks, active);
+        let _ = sink.join();
     }
 
-    fn connect() -> WebSocket<MaybeTlsStream<TcpStream>> {
-        let mut port = "8001".to_string();
-        loop {
-            match tungstenite::connect(format!("ws://localhost:{}", port)) {
-                Ok((websocket, _responce)) => {
-                    info!("Connected to local port:{}", port);
-                    return websocket;
-                }
-                Err(error) => {
-                    warn!("{}", error);
-                    match VtsPc::discover_port() {
-                        Ok(prt) => {
-                            port = prt;
-                        }
-                        Err(e) => {
-                            warn!("{}", e);
-                            continue;
-                        }
-                    }
+    /// Find VTubeStudio instances by their API state broadcasts during `duration`
+    pub fn discover(duration: time::Duration) -> Result<Vec<VtsInstance>, String> {
+        let socket = VtsPc::discovery_socket().map_err(|e| e.to_string())?;
+        socket
+            .set_read_timeout(Some(time::Duration::from_millis(100)))
+            .map_err(|e| e.to_string())?;
+
+        let mut buf = [0; 4096];
+        let mut instances: Vec<VtsInstance> = Vec::new();
+        let end = time::Instant::now() + duration;
+
+        while time::Instant::now() < end {
+            let (amt, src) = match socket.recv_from(&mut buf) {
+                Ok(m) => m,
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue
                 }
+                Err(error) => return Err(error.to_string()),
+            };
+            let data: VTSApiResponce<responces::Discovery> =
+                match serde_json::from_slice(&buf[..amt]) {
+                    Ok(d) => d,
+                    Err(_) => continue,
+                };
+
+            let instance = VtsInstance {
+                ip: src.ip(),
+                info: data.data,
+            };
+            match instances
+                .iter_mut()
+                .find(|i| i.ip == instance.ip && i.info.instance_id == instance.info.instance_id)
+            {
+                Some(known) => *known = instance,
+                None => instances.push(instance),
             }
         }
+        Ok(instances)
     }
 
-    fn discover_port() -> Result<String, String> {
-        let mut buf = [0; 4096];
+    /// Shared with other plugins listening for broadcasts on the same PC
+    fn discovery_socket() -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT));
+        socket.bind(&addr.into())?;
+        Ok(socket.into())
+    }
+}
 
-        let discovery_socket = match UdpSocket::bind("0.0.0.0:47779") {
-            Ok(s) => s,
-            Err(e) => return Err(e.to_string()),
-        };
+/// VTubeStudio broadcasts its API state on this UDP port
+const DISCOVERY_PORT: u16 = 47779;
 
-        match discovery_socket.set_read_timeout(Some(core::time::Duration::from_secs(3))) {
-            Ok(m) => m,
-            Err(e) => return Err(e.to_string()),
-        };
+/// VTubeStudio broadcasts every few seconds
+const DISCOVERY_TIME: time::Duration = time::Duration::from_secs(3);
 
-        let (amt, _src) = match discovery_socket.recv_from(&mut buf) {
-            Ok(m) => m,
-            Err(e) => return Err(e.to_string()),
-        };
+/// VTubeStudio instance found by discovery
+#[derive(Debug, Clone)]
+pub struct VtsInstance {
+    pub ip: IpAddr,
+    pub info: responces::Discovery,
+}
+
+impl VtsInstance {
+    /// Pinned by instance id or exact window title
+    pub fn matches(&self, instance: &str) -> bool {
+        self.info.instance_id == instance || self.info.window_title == instance
+    }
+}
+
+impl fmt::Display for VtsInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} \"{}\" (id: {})",
+            self.ip, self.info.port, self.info.window_title, self.info.instance_id
+        )?;
+        if !self.info.active {
+            write!(f, " API disabled")?;
+        }
+        Ok(())
+    }
+}
+
+/// VTubeStudio forgets injected params after 1 second
+const KEEP_ALIVE: time::Duration = time::Duration::from_millis(500);
+
+/// Failed authentication is retried after this delay, doubled up to `RETRY_MAX`
+const RETRY_MIN: time::Duration = time::Duration::from_secs(5);
+const RETRY_MAX: time::Duration = time::Duration::from_secs(60);
+
+/// VTubeStudio answers every request, except token request waiting for user
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Remote VTubeStudio can be unreachable for long time
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VtsCfg {
+    /// Host of VTubeStudio, IP or hostname
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// Port of plugin API, discovered if not set
+    pub port: Option<u16>,
+    /// Instance id or window title of discovered instance, first one if not set
+    pub instance: Option<String>,
+    /// Every VTubeStudio instance gives its own token
+    #[serde(default = "default_token_file")]
+    pub token_file: String,
+    /// `wss://` instead of `ws://`, for VTubeStudio behind TLS proxy
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM file with CA certificate of the proxy, in addition to system certificates
+    pub ca_file: Option<String>,
+    /// Send only params that changed at least by this value, and all params twice a second
+    pub min_change: Option<f64>,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_token_file() -> String {
+    "token".to_string()
+}
 
-        let data: VTSApiResponce<responces::Discovery> = match serde_json::from_slice(&buf[..amt]) {
-            Ok(d) => d,
-            Err(e) => return Err(e.to_string()),
+impl Default for VtsCfg {
+    fn default() -> Self {
+        VtsCfg {
+            host: default_host(),
+            port: None,
+            instance: None,
+            token_file: default_token_file(),
+            tls: false,
+            ca_file: None,
+            min_change: None,
+        }
+    }
+}
+
+/// Request waiting for its responce
+enum Pending {
+    Status,
+    Token,
+    Auth,
+    Param(String),
+    Inject,
+    Hotkey(String),
+}
+
+impl Pending {
+    /// Params and hotkeys don't wait for responces of previous requests
+    fn pipelined(&self) -> bool {
+        matches!(self, Pending::Inject | Pending::Hotkey(_))
+    }
+
+    /// Token is sent after user accepts popup in VTubeStudio
+    fn timeout(&self) -> Option<time::Duration> {
+        match self {
+            Pending::Token => None,
+            _ => Some(REQUEST_TIMEOUT),
+        }
+    }
+}
+
+impl fmt::Display for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pending::Status => write!(f, "status"),
+            Pending::Token => write!(f, "token"),
+            Pending::Auth => write!(f, "authentication"),
+            Pending::Param(name) => write!(f, "param {} creation", name),
+            Pending::Inject => write!(f, "params injection"),
+            Pending::Hotkey(id) => write!(f, "hotkey {}", id),
+        }
+    }
+}
+
+/// Request that gets its id when sent
+struct Request {
+    kind: Pending,
+    message_type: &'static str,
+    data: Option<Value>,
+}
+
+impl Request {
+    fn new<T: serde::Serialize>(
+        kind: Pending,
+        message_type: &'static str,
+        data: Option<T>,
+    ) -> Request {
+        Request {
+            kind,
+            message_type,
+            data: data.map(|data| serde_json::to_value(data).unwrap()),
+        }
+    }
+}
+
+/// VTubeStudio plugin API as sink, connects on first params and reconnects after errors
+pub struct VtsSink {
+    host: String,
+    port: u16,
+    /// Port is discovered when not set
+    fixed_port: bool,
+    instance: Option<String>,
+    /// Port of pinned instance is known
+    discovered: bool,
+    /// Discovery runs in background, so sending isn't blocked while it listens for broadcasts
+    discovery: Option<thread::JoinHandle<Result<u16, String>>>,
+    token_file: String,
+    tls: bool,
+    connector: Option<TlsConnector>,
+    websocket: Option<WebSocket<MaybeTlsStream<TcpStream>>>,
+    token: Option<String>,
+    params: Arc<Vec<ParamDef>>,
+    /// Status, auth and param creation requests, sent one by one before params
+    msg_buffer: VecDeque<Request>,
+    /// Sent requests by request id
+    pending: HashMap<String, (Pending, time::Instant)>,
+    next_id: u64,
+    /// Previous message is still in socket buffer, VTubeStudio is slower than tracking
+    congested: bool,
+    /// Injection errors are logged once until params are injected again
+    inject_failing: bool,
+    min_change: Option<f64>,
+    /// Last injected value by param
+    sent: HashMap<String, f64>,
+    /// When all params were injected
+    keep_alive_at: Option<time::Instant>,
+    monitor: VtsMonitor,
+    /// Buffered requests wait until then after failed authentication
+    retry_at: Option<time::Instant>,
+    backoff: time::Duration,
+}
+
+impl VtsSink {
+    /// Fails when CA file can't be loaded
+    pub fn new(cfg: VtsCfg, monitor: VtsMonitor) -> Result<VtsSink, String> {
+        let connector = match &cfg.ca_file {
+            Some(path) => {
+                let pem = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+                let ca = Certificate::from_pem(&pem).map_err(|e| format!("{}: {}", path, e))?;
+                let tls = TlsConnector::builder()
+                    .add_root_certificate(ca)
+                    .build()
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                Some(tls)
+            }
+            None => None,
         };
 
-        Ok(data.data.port.to_string())
+        Ok(VtsSink {
+            host: cfg.host,
+            port: cfg.port.unwrap_or(8001),
+            fixed_port: cfg.port.is_some(),
+            instance: cfg.instance,
+            discovered: false,
+            discovery: None,
+            token_file: cfg.token_file,
+            tls: cfg.tls,
+            connector,
+            websocket: None,
+            token: None,
+            params: Arc::new(Vec::new()),
+            msg_buffer: VecDeque::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+            congested: false,
+            inject_failing: false,
+            min_change: cfg.min_change,
+            sent: HashMap::new(),
+            keep_alive_at: None,
+            monitor,
+            retry_at: None,
+            backoff: RETRY_MIN,
+        })
     }
 
-    fn msg_loop(
-        mut websocket: WebSocket<MaybeTlsStream<TcpStream>>,
-        receiver: &Receiver<TrackingResponce>,
-        transformation_cfg_path: &String,
-        active: Arc<AtomicBool>,
-    ) {
-        let mut msg_buffer: VecDeque<Message> = VecDeque::new();
-        let mut token: Option<String> = fs::read_to_string("token").ok();
+    fn url(&self) -> String {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        match self.host.parse::<Ipv6Addr>() {
+            Ok(ip) => format!("{}://[{}]:{}", scheme, ip, self.port),
+            Err(_) => format!("{}://{}:{}", scheme, self.host, self.port),
+        }
+    }
 
-        msg_buffer.push_back(VtsPc::req_status_msg());
+    fn connect(&mut self) -> Result<(), String> {
+        if self.websocket.is_some() {
+            return Ok(());
+        }
+        let discovery = self.discovered_port();
+        // Default port can belong to another instance
+        if let Some(instance) = self.instance.as_ref().filter(|_| !self.fixed_port) {
+            if !self.discovered {
+                let waiting = format!("Discovering VTubeStudio instance {}", instance);
+                self.start_discovery();
+                discovery?;
+                return Err(waiting);
+            }
+        }
 
-        let (precalc_funcs, mut new_params) = VtsPc::precalc_cfg(transformation_cfg_path);
+        let connected = self.connect_tcp().and_then(|stream| {
+            // Handshake is blocking but limited, after it reads and writes never wait for VTubeStudio
+            let tcp = stream.try_clone().map_err(|e| e.to_string())?;
+            tcp.set_read_timeout(Some(CONNECT_TIMEOUT))
+                .and_then(|_| tcp.set_write_timeout(Some(CONNECT_TIMEOUT)))
+                .map_err(|e| e.to_string())?;
+            let (websocket, _responce) = tungstenite::client_tls_with_config(
+                self.url(),
+                stream,
+                None,
+                self.connector.clone().map(Connector::NativeTls),
+            )
+            .map_err(|e| e.to_string())?;
+            tcp.set_read_timeout(None)
+                .and_then(|_| tcp.set_write_timeout(None))
+                .and_then(|_| tcp.set_nonblocking(true))
+                .map_err(|e| e.to_string())?;
+            Ok(websocket)
+        });
+
+        match connected {
+            Ok(websocket) => {
+                info!("Connected to {}", self.url());
+                self.websocket = Some(websocket);
+                self.set_state(VtsState::Connected);
+                self.token = fs::read_to_string(&self.token_file).ok();
+                self.msg_buffer.clear();
+                self.pending.clear();
+                self.congested = false;
+                self.sent.clear();
+                self.keep_alive_at = None;
+                self.retry_at = None;
+                self.msg_buffer.push_back(VtsSink::req_status());
+                self.msg_buffer
+                    .append(&mut VtsSink::param_reqs(&self.params));
+                Ok(())
+            }
+            Err(error) => {
+                if !self.fixed_port {
+                    self.start_discovery();
+                }
+                Err(error)
+            }
+        }
+    }
 
-        msg_buffer.append(&mut new_params);
+    /// First address of host that accepts connection, hostname can resolve to IPv6 and IPv4
+    fn connect_tcp(&self) -> Result<TcpStream, String> {
+        let addrs = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("{}: {}", self.host, e))?;
+        let mut error = format!("No address for {}", self.host);
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = format!("{}: {}", addr, e),
+            }
+        }
+        Err(error)
+    }
 
-        // let interval = time::Duration::from_millis(30);
-        // let mut next_time = std::time::Instant::now() + interval;
+    fn start_discovery(&mut self) {
+        if self.discovery.is_some() {
+            return;
+        }
+        let host = self.host.clone();
+        let instance = self.instance.clone();
+        self.discovery = Some(thread::spawn(move || {
+            VtsSink::discover_port(&host, instance.as_deref())
+        }));
+    }
 
-        let mut dont_send = false;
+    /// Takes port of finished discovery, error when instance wasn't found
+    fn discovered_port(&mut self) -> Result<(), String> {
+        let discovery = match self.discovery.take() {
+            Some(discovery) if discovery.is_finished() => discovery,
+            running => {
+                self.discovery = running;
+                return Ok(());
+            }
+        };
+        match discovery.join() {
+            Ok(Ok(port)) => {
+                self.port = port;
+                self.discovered = true;
+                Ok(())
+            }
+            Ok(Err(error)) => {
+                self.discovered = false;
+                Err(error)
+            }
+            Err(_) => {
+                self.discovered = false;
+                Err("VTubeStudio discovery failed".to_string())
+            }
+        }
+    }
 
-        while active.load(Ordering::Relaxed) {
-            if !dont_send {
-                if let Some(msg) = msg_buffer.front() {
-                    match websocket.send(msg.clone()) {
-                        Ok(_) => {}
-                        Err(error) => {
-                            warn!("Unable to send msg: {}", error);
-                            break; // Reconnect
-                        }
-                    }
-                } else {
-                    let tracking_data = VtsPc::tracking_msg(&precalc_funcs, receiver);
-                    if tracking_data.is_some() {
-                        match websocket.send(tracking_data.unwrap()) {
-                            Ok(_) => {}
-                            Err(error) => {
-                                warn!("Unable to send tracking msg: {}", error);
-                                break; // Reconnect
-                            }
-                        }
-                    } else {
-                        continue;
-                    }
+    /// Port from VTubeStudio broadcasts, only broadcasts sent by host are used
+    fn discover_port(host: &str, pinned: Option<&str>) -> Result<u16, String> {
+        let host_ips: Vec<IpAddr> = (host, 0)
+            .to_socket_addrs()
+            .map_err(|e| format!("{}: {}", host, e))?
+            .map(|addr| addr.ip())
+            .collect();
+        // Broadcasts of this PC come from its LAN address, which can be bound only locally
+        let local = host_ips.iter().any(|ip| ip.is_loopback());
+        let from_host =
+            |ip: IpAddr| host_ips.contains(&ip) || (local && UdpSocket::bind((ip, 0)).is_ok());
+
+        let instances: Vec<VtsInstance> = VtsPc::discover(DISCOVERY_TIME)?
+            .into_iter()
+            .filter(|instance| instance.info.active && from_host(instance.ip))
+            .collect();
+
+        let instance = match pinned {
+            Some(pinned) => instances
+                .iter()
+                .find(|instance| instance.matches(pinned))
+                .ok_or_else(|| format!("VTubeStudio instance {} not found", pinned))?,
+            None => {
+                let first = instances
+                    .first()
+                    .ok_or_else(|| "No VTubeStudio instance found".to_string())?;
+                if instances.len() > 1 {
+                    warn!(
+                        "Found {} VTubeStudio instances, using {}, set instance to choose",
+                        instances.len(),
+                        first
+                    );
                 }
+                first
             }
+        };
+        Ok(instance.info.port)
+    }
+
+    fn set_state(&self, state: VtsState) {
+        self.monitor.set_state(&self.name(), state);
+    }
+
+    fn disconnect(&mut self) {
+        self.websocket = None;
+        self.set_state(VtsState::Disconnected);
+    }
+
+    /// Request is sent again after backoff, doubled on every failure
+    fn retry(&mut self, request: Request) {
+        info!(
+            "Retrying {} request in {} s",
+            request.kind,
+            self.backoff.as_secs()
+        );
+        self.msg_buffer.push_front(request);
+        self.retry_at = Some(time::Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(RETRY_MAX);
+    }
+
+    fn authenticated(&mut self) {
+        self.backoff = RETRY_MIN;
+        self.set_state(VtsState::Authenticated);
+    }
+
+    /// Send request with new id without waiting for responce, connection is dropped on error
+    fn write(&mut self, request: Request) -> Result<(), String> {
+        self.next_id += 1;
+        let request_id = self.next_id.to_string();
+        let msg = VTSApiRequest {
+            data: request.data,
+            api_name: "VTubeStudioPublicAPI",
+            api_version: "1.0",
+            request_id: &request_id,
+            message_type: request.message_type,
+        };
+        let msg = Message::text(serde_json::to_string(&msg).unwrap());
+
+        let websocket = self.websocket.as_mut().ok_or("Not connected")?;
+        let result = websocket.send(msg);
+        self.written(result)?;
+        self.pending
+            .insert(request_id, (request.kind, time::Instant::now()));
+        Ok(())
+    }
 
+    /// Continue sending messages that didn't fit into socket buffer
+    fn flush(&mut self) -> Result<(), String> {
+        let websocket = self.websocket.as_mut().ok_or("Not connected")?;
+        let result = websocket.flush();
+        self.written(result)
+    }
+
+    /// Message that would block stays queued in websocket until next flush
+    fn written(&mut self, result: tungstenite::Result<()>) -> Result<(), String> {
+        match result {
+            Ok(()) => {
+                self.congested = false;
+                Ok(())
+            }
+            Err(tungstenite::Error::Io(error)) if error.kind() == io::ErrorKind::WouldBlock => {
+                self.congested = true;
+                Ok(())
+            }
+            Err(error) => {
+                self.disconnect();
+                Err(error.to_string())
+            }
+        }
+    }
+
+    /// Handle every responce that already arrived, connection is dropped on error
+    /// or when VTubeStudio doesn't answer
+    fn read(&mut self) -> Result<(), String> {
+        loop {
+            let websocket = self.websocket.as_mut().ok_or("Not connected")?;
             match websocket.read() {
-                Ok(msg) => {
-                    if msg.is_text() {
-                        let msg_value =
-                            serde_json::from_str::<Value>(msg.to_text().unwrap()).unwrap();
-
-                        match msg_value["messageType"].as_str() {
-                            Some(msg_type) => match msg_type {
-                                "APIError" => {
-                                    let err_data = serde_json::from_value::<
-                                        VTSApiResponce<responces::APIError>,
-                                    >(msg_value)
-                                    .unwrap();
-                                    // warn!("API error: {:?}", err_data.data);
-                                    match err_data.data.error_id {
-                                        8 => {
-                                            // msg_buffer.push_back(VtsPc::auth(&token));
-                                        }
-                                        51 => {
-                                            // POPUP ON SCREEN
-
-                                            // MAYBE
-                                            // DELAY
-                                            // msg_buffer.push_back(VtsPc::auth(&token));
-                                        }
-                                        352 => {
-                                            // custom parameter exist
-                                            msg_buffer.pop_front();
-                                        }
-                                        354 => {
-                                            // custom parameter is default
-                                            msg_buffer.pop_front();
-                                        }
-                                        450 => {
-                                            //No param data was sended
-                                        }
-                                        _ => error!("Unknown API error: {:?}", err_data.data),
-                                    }
-                                }
-                                "APIStateResponse" => {
-                                    let state_data =
-                                        serde_json::from_value::<
-                                            VTSApiResponce<responces::APIStateResponse>,
-                                        >(msg_value)
-                                        .unwrap();
-                                    msg_buffer.pop_front();
-                                    if !state_data.data.current_session_authenticated {
-                                        msg_buffer.push_front(VtsPc::auth(&token));
-                                    }
-                                }
-                                "AuthenticationTokenResponse" => {
-                                    let token_data =
-                                        serde_json::from_value::<
-                                            VTSApiResponce<responces::AuthenticationToken>,
-                                        >(msg_value)
-                                        .unwrap();
-
-                                    let _ =
-                                        fs::write("token", &token_data.data.authentication_token)
-                                            .map_err(|e| error!("Unable to save token: {:?}", e));
-                                    token = Some(token_data.data.authentication_token);
-                                    info!("Recived Token from VtubeStudio");
-                                    msg_buffer.pop_front();
-                                    msg_buffer.push_front(VtsPc::auth(&token));
-                                }
-                                "AuthenticationResponse" => {
-                                    let auth_data = serde_json::from_value::<
-                                        VTSApiResponce<responces::AuthenticationResponse>,
-                                    >(msg_value)
-                                    .unwrap();
-                                    msg_buffer.pop_front();
-                                    if !auth_data.data.authenticated {
-                                        token = None;
-                                        let _ = fs::remove_file("token")
-                                            .map_err(|e| error!("Unable to delete token: {:?}", e));
-                                        info!("Invalid Token, Requesting new...");
-                                        msg_buffer.push_back(VtsPc::auth(&token));
-                                    }
-                                }
-                                "InjectParameterDataResponse" => {
-                                    // println!("{:?}", msg);
-                                }
-                                "ParameterCreationResponse" => {
-                                    // println!("{:?}", msg);
-                                    msg_buffer.pop_front();
-                                }
-                                _ => warn!("Unknown message: {}", msg_value["messageType"]),
-                            },
-                            None => warn!("No type in responce: {}", msg.to_text().unwrap()),
-                        }
-                        dont_send = false;
-                    } else if msg.is_ping() || msg.is_pong() {
-                        dont_send = true;
-                        continue;
-                    } else {
-                        warn!("Non text response: {:?}", msg);
-                        continue;
-                    }
+                Ok(msg) if msg.is_text() => self.handle(msg.to_text().unwrap()),
+                Ok(msg) if msg.is_ping() || msg.is_pong() => {}
+                Ok(msg) => warn!("Non text response: {:?}", msg),
+                Err(tungstenite::Error::Io(error)) if error.kind() == io::ErrorKind::WouldBlock => {
+                    break
                 }
                 Err(error) => {
-                    warn!("Unable to read msg: {}", error);
-                    break; // Reconnect
+                    self.disconnect();
+                    return Err(error.to_string());
                 }
             }
+        }
 
-            //rate limit
-            // thread::sleep(next_time - std::time::Instant::now());
-            // next_time += interval;
+        let timed_out = self.pending.values().find(|(kind, sent)| {
+            kind.timeout()
+                .is_some_and(|timeout| sent.elapsed() > timeout)
+        });
+        if let Some((kind, _)) = timed_out {
+            let error = format!("No responce to {} request", kind);
+            self.disconnect();
+            return Err(error);
         }
+        Ok(())
     }
 
-    fn tracking_msg(
-        precalc_funcs: &Vec<(String, Node)>,
-        receiver: &Receiver<TrackingResponce>,
-    ) -> Option<Message> {
-        let mut context = HashMapContext::new();
+    /// Buffered request is sent when previous one got its responce
+    fn awaiting(&self) -> bool {
+        self.pending.values().any(|(kind, _)| !kind.pipelined())
+    }
 
-        let mut binding = receiver.try_iter();
-        let it = binding.by_ref();
+    fn send_buffered(&mut self) -> Result<(), String> {
+        if self.awaiting() {
+            return Ok(());
+        }
+        if let Some(retry_at) = self.retry_at {
+            if retry_at > time::Instant::now() {
+                return Ok(());
+            }
+            self.retry_at = None;
+        }
+        let request = match self.msg_buffer.pop_front() {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        match request.kind {
+            Pending::Token => self.set_state(VtsState::WaitingForUser),
+            Pending::Auth => self.set_state(VtsState::Authenticating),
+            _ => {}
+        }
+        self.write(request)
+    }
+
+    fn handle(&mut self, msg: &str) {
+        let msg_value = match serde_json::from_str::<Value>(msg) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Invalid responce from VTubeStudio: {}", e);
+                return;
+            }
+        };
 
-        let raw_data = match it.last() {
-            Some(data) => data,
+        let kind = match msg_value["requestID"]
+            .as_str()
+            .and_then(|id| self.pending.remove(id))
+        {
+            Some((kind, _sent)) => kind,
             None => {
-                return None;
+                warn!("Responce to unknown request: {}", msg);
+                return;
             }
         };
 
-        for v in &raw_data.blend_shapes {
-            context.set_value(v.k.clone(), v.v.into()).unwrap();
-        }
-
-        context
-            .set_value("HeadPosX".into(), raw_data.position.x.into())
-            .unwrap();
-        context
-            .set_value("HeadPosY".into(), raw_data.position.y.into())
-            .unwrap();
-        context
-            .set_value("HeadPosZ".into(), raw_data.position.z.into())
-            .unwrap();
-
-        context
-            .set_value("HeadRotX".into(), raw_data.rotation.x.into())
-            .unwrap();
-        context
-            .set_value("HeadRotY".into(), raw_data.rotation.y.into())
-            .unwrap();
-        context
-            .set_value("HeadRotZ".into(), raw_data.rotation.z.into())
-            .unwrap();
-
-        let mut params: Vec<requests::TrackingParam> = Vec::new();
-
-        if raw_data.face_found {
-            for c in precalc_funcs {
-                params.push(requests::TrackingParam {
-                    id: c.0.as_str(),
-                    value: c
-                        .1
-                        .eval_with_context(&context)
-                        .unwrap()
-                        .as_float()
-                        .unwrap()
-                        .clamp(-1000000.0, 1000000.0),
-                    weight: Some(1.0),
-                });
+        match (kind, msg_value["messageType"].as_str()) {
+            (kind, Some("APIError")) => {
+                match serde_json::from_value::<VTSApiResponce<responces::APIError>>(msg_value) {
+                    Ok(err_data) => self.handle_error(kind, err_data.data),
+                    Err(e) => warn!("Invalid API error: {}", e),
+                }
+            }
+            (Pending::Status, Some("APIStateResponse")) => {
+                let state_data = match serde_json::from_value::<
+                    VTSApiResponce<responces::APIStateResponse>,
+                >(msg_value)
+                {
+                    Ok(state_data) => state_data,
+                    Err(e) => {
+                        warn!("Invalid API state: {}", e);
+                        self.retry(VtsSink::req_status());
+                        return;
+                    }
+                };
+                if state_data.data.current_session_authenticated {
+                    self.authenticated();
+                } else {
+                    self.msg_buffer.push_front(VtsSink::auth(&self.token));
+                }
+            }
+            (Pending::Token, Some("AuthenticationTokenResponse")) => {
+                let token_data = match serde_json::from_value::<
+                    VTSApiResponce<responces::AuthenticationToken>,
+                >(msg_value)
+                {
+                    Ok(token_data) => token_data,
+                    Err(e) => {
+                        warn!("Invalid authentication token: {}", e);
+                        self.retry(VtsSink::auth(&self.token));
+                        return;
+                    }
+                };
+
+                let _ = fs::write(&self.token_file, &token_data.data.authentication_token)
+                    .map_err(|e| error!("Unable to save token: {:?}", e));
+                self.token = Some(token_data.data.authentication_token);
+                info!("Recived Token from VtubeStudio");
+                self.msg_buffer.push_front(VtsSink::auth(&self.token));
             }
+            (Pending::Auth, Some("AuthenticationResponse")) => {
+                let auth_data = match serde_json::from_value::<
+                    VTSApiResponce<responces::AuthenticationResponse>,
+                >(msg_value)
+                {
+                    Ok(auth_data) => auth_data,
+                    Err(e) => {
+                        warn!("Invalid authentication responce: {}", e);
+                        self.retry(VtsSink::auth(&self.token));
+                        return;
+                    }
+                };
+                if auth_data.data.authenticated {
+                    self.authenticated();
+                } else {
+                    self.token = None;
+                    let _ = fs::remove_file(&self.token_file)
+                        .map_err(|e| error!("Unable to delete token: {:?}", e));
+                    info!("Invalid Token, Requesting new...");
+                    self.msg_buffer.push_front(VtsSink::auth(&self.token));
+                }
+            }
+            (Pending::Param(_), Some("ParameterCreationResponse")) => {}
+            (Pending::Inject, Some("InjectParameterDataResponse")) => self.inject_failing = false,
+            (Pending::Hotkey(_), Some("HotkeyTriggerResponse")) => {}
+            (kind, _) => warn!(
+                "Unknown responce to {} request: {}",
+                kind, msg_value["messageType"]
+            ),
         }
+    }
 
-        if params.is_empty() {
-            return None;
+    fn handle_error(&mut self, kind: Pending, error: responces::APIError) {
+        match kind {
+            // session is not authenticated anymore
+            Pending::Inject if error.error_id == 8 => {
+                if self.monitor.state() == VtsState::Authenticated {
+                    self.set_state(VtsState::Connected);
+                    self.msg_buffer.push_front(VtsSink::req_status());
+                }
+            }
+            // custom parameter exist or is default
+            Pending::Param(_) if error.error_id == 352 || error.error_id == 354 => {}
+            Pending::Param(name) => warn!("Unable to create param {}: {}", name, error.message),
+            Pending::Inject => {
+                if !self.inject_failing {
+                    self.inject_failing = true;
+                    warn!("Unable to inject params: {}", error.message);
+                }
+            }
+            Pending::Hotkey(id) => warn!("Unable to trigger hotkey {}: {}", id, error.message),
+            Pending::Status => {
+                error!("Unknown API error: {:?}", error);
+                self.retry(VtsSink::req_status());
+            }
+            // user denied the plugin, asked again only after reconnect or profile switch
+            Pending::Token if error.error_id == 50 => {
+                self.set_state(VtsState::Denied);
+                self.backoff = RETRY_MIN;
+                // params are created again after authentication
+                self.msg_buffer.clear();
+            }
+            // popup of previous token request is still shown
+            Pending::Token if error.error_id == 51 => {
+                self.retry(VtsSink::auth(&self.token));
+            }
+            Pending::Token | Pending::Auth => {
+                error!("Unknown API error: {:?}", error);
+                self.retry(VtsSink::auth(&self.token));
+            }
         }
+    }
 
-        let params_data = requests::InjectParams {
-            face_found: raw_data.face_found,
-            mode: "set",
-            parameter_values: params,
+    /// Params that changed at least by `min_change` since they were injected,
+    /// or all params when VTubeStudio would forget them
+    fn changed<'a>(&self, params: &'a Params) -> Option<Cow<'a, Params>> {
+        let min_change = match self.min_change {
+            Some(min_change) => min_change,
+            None => return Some(Cow::Borrowed(params)),
         };
 
-        let message_type = "InjectParameterDataRequest";
-
-        let request = VTSApiRequest {
-            data: Some(params_data),
-            api_name: "VTubeStudioPublicAPI",
-            api_version: "1.0",
-            request_id: "iiii",
-            message_type,
-        };
+        let keep_alive = self.keep_alive();
+        let values: Vec<ParamValue> = params
+            .values
+            .iter()
+            .filter(|param| {
+                keep_alive
+                    || self
+                        .sent
+                        .get(&param.id)
+                        .is_none_or(|old| (param.value - old).abs() >= min_change)
+            })
+            .cloned()
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        Some(Cow::Owned(Params { values, ..*params }))
+    }
 
-        let request_string = serde_json::to_string(&request).unwrap();
+    fn keep_alive(&self) -> bool {
+        self.keep_alive_at
+            .is_none_or(|sent| sent.elapsed() >= KEEP_ALIVE)
+    }
 
-        Some(Message::text(request_string))
+    /// Recorded only after params were written, so failed params are sent again
+    fn injected(&mut self, params: &Params, keep_alive: bool) {
+        if self.min_change.is_none() {
+            return;
+        }
+        if keep_alive {
+            self.keep_alive_at = Some(time::Instant::now());
+        }
+        for param in &params.values {
+            self.sent.insert(param.id.clone(), param.value);
+        }
     }
 
-    fn req_status_msg() -> Message {
-        let status_req = VTSApiRequest::<i32> {
-            data: None,
-            api_name: "VTubeStudioPublicAPI",
-            api_version: "1.0",
-            request_id: "iiii",
-            message_type: "APIStateRequest",
+    fn tracking_req(params: &Params) -> Request {
+        let params_data = requests::InjectParams {
+            face_found: params.face_found,
+            mode: "set",
+            parameter_values: params
+                .values
+                .iter()
+                .map(|param| requests::TrackingParam {
+                    id: param.id.as_str(),
+                    value: param.value,
+                    weight: Some(1.0),
+                })
+                .collect(),
         };
 
-        let status_req_msg = serde_json::to_string(&status_req).unwrap();
-        info!("Requesing status of VtubeStudio");
-        Message::text(status_req_msg)
+        Request::new(
+            Pending::Inject,
+            "InjectParameterDataRequest",
+            Some(params_data),
+        )
     }
 
-    fn auth(token: &Option<String>) -> Message {
-        if token.is_some() {
-            let tk = token.clone().unwrap();
+    fn hotkey_req(id: &str) -> Request {
+        info!("Triggering VtubeStudio hotkey: {}", id);
+        Request::new(
+            Pending::Hotkey(id.to_string()),
+            "HotkeyTriggerRequest",
+            Some(requests::HotkeyTrigger { hotkey_id: id }),
+        )
+    }
 
+    fn req_status() -> Request {
+        info!("Requesing status of VtubeStudio");
+        Request::new::<i32>(Pending::Status, "APIStateRequest", None)
+    }
+
+    fn auth(token: &Option<String>) -> Request {
+        if let Some(tk) = token {
             let auth_token = requests::Auth {
                 plugin_name: "RustyBridgeUi",
                 plugin_developer: "ovROG",
                 authentication_token: tk.as_str(),
             };
 
-            let auth_req = VTSApiRequest {
-                data: Some(auth_token),
-                api_name: "VTubeStudioPublicAPI",
-                api_version: "1.0",
-                request_id: "iiii",
-                message_type: "AuthenticationRequest",
-            };
-
-            let auth_req_msg = serde_json::to_string(&auth_req).unwrap();
-
             info!("Authentication Request to VtubeStudio");
-            return Message::text(auth_req_msg);
+            return Request::new(Pending::Auth, "AuthenticationRequest", Some(auth_token));
         }
 
         let auth_data = requests::AuthToken {
@@ -483,93 +1012,111 @@ impl VtsPc {
             plugin_icon: None,
         };
 
-        let token_req = VTSApiRequest {
-            data: Some(auth_data),
-            api_name: "VTubeStudioPublicAPI",
-            api_version: "1.0",
-            request_id: "iiii",
-            message_type: "AuthenticationTokenRequest",
-        };
-
-        let token_req_msg = serde_json::to_string(&token_req).unwrap();
-
         info!("Authentication Token Request: Please accept PopUp in VtubeStudio");
-        Message::text(token_req_msg)
+        Request::new(
+            Pending::Token,
+            "AuthenticationTokenRequest",
+            Some(auth_data),
+        )
     }
 
-    fn precalc_cfg(file_path: &String) -> (Vec<(String, evalexpr::Node)>, VecDeque<Message>) {
-        info!("Loadling tranformation config: {}", file_path);
-
+    /// Creation requests for params that are not VTubeStudio default params
+    fn param_reqs(params: &[ParamDef]) -> VecDeque<Request> {
         let def_params = [
-            String::from("FacePositionX"),
-            String::from("FacePositionY"),
-            String::from("FacePositionZ"),
-            String::from("FaceAngleX"),
-            String::from("FaceAngleY"),
-            String::from("FaceAngleZ"),
-            String::from("MouthSmile"),
-            String::from("MouthOpen"),
-            String::from("Brows"),
-            String::from("TongueOut"),
-            String::from("EyeOpenLeft"),
-            String::from("EyeOpenRight"),
-            String::from("EyeLeftX"),
-            String::from("EyeLeftY"),
-            String::from("EyeRightX"),
-            String::from("EyeRightY"),
-            String::from("CheekPuff"),
-            String::from("FaceAngry"),
-            String::from("BrowLeftY"),
-            String::from("BrowRightY"),
-            String::from("MouthX"),
-            String::from("VoiceFrequencyPlusMouthSmile"),
+            "FacePositionX",
+            "FacePositionY",
+            "FacePositionZ",
+            "FaceAngleX",
+            "FaceAngleY",
+            "FaceAngleZ",
+            "MouthSmile",
+            "MouthOpen",
+            "Brows",
+            "TongueOut",
+            "EyeOpenLeft",
+            "EyeOpenRight",
+            "EyeLeftX",
+            "EyeLeftY",
+            "EyeRightX",
+            "EyeRightY",
+            "CheekPuff",
+            "FaceAngry",
+            "BrowLeftY",
+            "BrowRightY",
+            "MouthX",
+            "VoiceFrequencyPlusMouthSmile",
         ];
 
-        let mut new_params: VecDeque<Message> = VecDeque::new();
-        let config = fs::read_to_string(file_path).unwrap();
-        let calc_fns: Vec<CalcFn> = serde_json::from_str(&config[..]).unwrap();
-
-        let precalc_fns: Vec<_> = calc_fns
-            .into_iter()
-            .map(|func| {
-                (func.name.clone(), {
-                    info!("Loading Param: {}", &func.name);
-                    if !def_params.contains(&func.name) {
-                        let param_data = requests::ParameterCreation {
-                            parameter_name: func.name,
-                            explanation: "Custom rusty-bridge param".to_string(),
-                            min: func.min,
-                            max: func.max,
-                            default_value: func.default_value,
-                        };
-
-                        let param_req = VTSApiRequest {
-                            data: Some(param_data),
-                            api_name: "VTubeStudioPublicAPI",
-                            api_version: "1.0",
-                            request_id: "iiii",
-                            message_type: "ParameterCreationRequest",
-                        };
-
-                        let param_req_msg = serde_json::to_string(&param_req).unwrap();
-
-                        new_params.push_back(Message::text(param_req_msg));
-                    }
-                    match evalexpr::build_operator_tree(&func.func[..]) {
-                        Ok(calc) => calc,
-                        Err(error) => {
-                            error!(
-                                "Unable to read cfg (probably error or typo in function): {}",
-                                error
-                            );
-                            panic!()
-                        }
-                    }
-                })
+        params
+            .iter()
+            .filter(|param| !def_params.contains(&param.name.as_str()))
+            .map(|param| {
+                info!("Loading Param: {}", &param.name);
+                let param_data = requests::ParameterCreation {
+                    parameter_name: param.name.clone(),
+                    explanation: "Custom rusty-bridge param".to_string(),
+                    min: param.min,
+                    max: param.max,
+                    default_value: param.default_value,
+                };
+
+                Request::new(
+                    Pending::Param(param.name.clone()),
+                    "ParameterCreationRequest",
+                    Some(param_data),
+                )
             })
-            .collect();
+            .collect()
+    }
+}
+
+impl TrackingSink for VtsSink {
+    fn name(&self) -> String {
+        match self.fixed_port {
+            true => format!("VTubeStudio {}:{}", self.host, self.port),
+            false => format!("VTubeStudio {}", self.host),
+        }
+    }
+
+    fn define(&mut self, params: &[ParamDef]) -> Result<(), String> {
+        self.params = Arc::new(params.to_vec());
+        self.sent.clear();
+        self.keep_alive_at = None;
+        if self.websocket.is_some() {
+            self.msg_buffer.append(&mut VtsSink::param_reqs(params));
+            if self.monitor.state() == VtsState::Denied {
+                self.msg_buffer.push_front(VtsSink::auth(&self.token));
+            }
+        }
+        Ok(())
+    }
+
+    /// Params are sent after buffered requests (status, auth, param creation) without waiting for responces,
+    /// and skipped while previous params are still in socket buffer or plugin is denied
+    fn send(&mut self, params: &Params) -> Result<(), String> {
+        self.connect()?;
+        self.read()?;
+        self.flush()?;
+        if self.awaiting() || !self.msg_buffer.is_empty() {
+            return self.send_buffered();
+        }
+        if self.congested || self.monitor.state() == VtsState::Denied {
+            return Ok(());
+        }
+        let keep_alive = self.keep_alive();
+        match self.changed(params) {
+            Some(params) => {
+                self.write(VtsSink::tracking_req(&params))?;
+                self.injected(&params, keep_alive);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
 
-        info!("Tranformation config loaded");
-        (precalc_fns, new_params)
+    fn trigger_hotkey(&mut self, id: &str) -> Result<(), String> {
+        self.connect()?;
+        self.read()?;
+        self.write(VtsSink::hotkey_req(id))
     }
 }
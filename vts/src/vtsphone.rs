@@ -1,29 +1,297 @@
 use std::{
-    net::UdpSocket,
+    borrow::Cow,
+    collections::HashSet,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::Sender,
         Arc,
     },
     time,
 };
 
-use log::warn;
+use log::{error, info, warn};
+use serde::{
+    de::{SeqAccess, Visitor},
+    ser::SerializeSeq,
+};
+
+use crate::{
+    health::{PhoneMonitor, PhoneState, PhoneTracker},
+    slot::SlotSender,
+};
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
 pub struct Cords {
     pub x: f64,
     pub y: f64,
     pub z: f64,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Shape {
     pub k: String,
     pub v: f64,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+macro_rules! blend_shapes {
+    ($($name:ident),* $(,)?) => {
+        /// ARKit face blend shapes sent by the phone
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum BlendShape {
+            $($name),*
+        }
+
+        impl BlendShape {
+            pub const ALL: [BlendShape; BlendShape::COUNT] = [$(BlendShape::$name),*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(BlendShape::$name => stringify!($name)),*
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<BlendShape> {
+                match name {
+                    $(stringify!($name) => Some(BlendShape::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+blend_shapes!(
+    BrowDownLeft,
+    BrowDownRight,
+    BrowInnerUp,
+    BrowOuterUpLeft,
+    BrowOuterUpRight,
+    CheekPuff,
+    CheekSquintLeft,
+    CheekSquintRight,
+    EyeBlinkLeft,
+    EyeBlinkRight,
+    EyeLookDownLeft,
+    EyeLookDownRight,
+    EyeLookInLeft,
+    EyeLookInRight,
+    EyeLookOutLeft,
+    EyeLookOutRight,
+    EyeLookUpLeft,
+    EyeLookUpRight,
+    EyeSquintLeft,
+    EyeSquintRight,
+    EyeWideLeft,
+    EyeWideRight,
+    JawForward,
+    JawLeft,
+    JawOpen,
+    JawRight,
+    MouthClose,
+    MouthDimpleLeft,
+    MouthDimpleRight,
+    MouthFrownLeft,
+    MouthFrownRight,
+    MouthFunnel,
+    MouthLeft,
+    MouthLowerDownLeft,
+    MouthLowerDownRight,
+    MouthPressLeft,
+    MouthPressRight,
+    MouthPucker,
+    MouthRight,
+    MouthRollLower,
+    MouthRollUpper,
+    MouthShrugLower,
+    MouthShrugUpper,
+    MouthSmileLeft,
+    MouthSmileRight,
+    MouthStretchLeft,
+    MouthStretchRight,
+    MouthUpperUpLeft,
+    MouthUpperUpRight,
+    NoseSneerLeft,
+    NoseSneerRight,
+    TongueOut,
+);
+
+impl BlendShape {
+    pub const COUNT: usize = 52;
+}
+
+impl fmt::Display for BlendShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for BlendShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BlendShape::from_name(s).ok_or_else(|| {
+            unknown_name(
+                "blend shape",
+                s,
+                BlendShape::ALL.iter().map(|shape| shape.name()),
+            )
+        })
+    }
+}
+
+/// Error message for unknown name with the closest known one as suggestion
+pub(crate) fn unknown_name<'a>(
+    kind: &str,
+    name: &str,
+    known: impl IntoIterator<Item = &'a str>,
+) -> String {
+    let closest = known
+        .into_iter()
+        .map(|k| (distance(&name.to_lowercase(), &k.to_lowercase()), k))
+        .min_by_key(|(d, _)| *d)
+        .filter(|(d, _)| *d <= (name.len() / 3).max(2));
+
+    match closest {
+        Some((_, k)) => format!("Unknown {} `{}`, did you mean `{}`?", kind, name, k),
+        None => format!("Unknown {} `{}`", kind, name),
+    }
+}
+
+/// Levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(cur).min(row[j])
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Blend shapes of a frame, ARKit shapes are stored without allocations
+///
+/// Sent over network in phone format: `[{"k": "JawOpen", "v": 0.5}, ...]`,
+/// names that are not ARKit shapes (from other inputs) are kept in `custom`
+#[derive(Debug, Clone)]
+pub struct BlendShapes {
+    values: [f64; BlendShape::COUNT],
+    /// Bit for every shape in `values` that is set
+    present: u64,
+    pub custom: Vec<Shape>,
+}
+
+impl Default for BlendShapes {
+    fn default() -> Self {
+        BlendShapes {
+            values: [0.0; BlendShape::COUNT],
+            present: 0,
+            custom: Vec::new(),
+        }
+    }
+}
+
+impl BlendShapes {
+    pub fn get(&self, shape: BlendShape) -> Option<f64> {
+        if self.present & (1 << shape as usize) == 0 {
+            return None;
+        }
+        Some(self.values[shape as usize])
+    }
+
+    pub fn set(&mut self, shape: BlendShape, value: f64) {
+        self.values[shape as usize] = value;
+        self.present |= 1 << shape as usize;
+    }
+
+    /// Set ARKit shape or custom value by name
+    pub fn insert(&mut self, name: &str, value: f64) {
+        match BlendShape::from_name(name) {
+            Some(shape) => self.set(shape, value),
+            None => match self.custom.iter_mut().find(|s| s.k == name) {
+                Some(shape) => shape.v = value,
+                None => self.custom.push(Shape {
+                    k: name.to_string(),
+                    v: value,
+                }),
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.present.count_ones() as usize + self.custom.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// ARKit shapes that are set, then custom ones
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> {
+        BlendShape::ALL
+            .iter()
+            .filter_map(|shape| self.get(*shape).map(|v| (shape.name(), v)))
+            .chain(self.custom.iter().map(|s| (s.k.as_str(), s.v)))
+    }
+}
+
+/// Shape with borrowed name, to read frames without allocations
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ShapeRef<'a> {
+    #[serde(borrow)]
+    k: Cow<'a, str>,
+    v: f64,
+}
+
+impl serde::Serialize for BlendShapes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for (k, v) in self.iter() {
+            seq.serialize_element(&ShapeRef {
+                k: Cow::Borrowed(k),
+                v,
+            })?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BlendShapes {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ShapesVisitor;
+
+        impl<'de> Visitor<'de> for ShapesVisitor {
+            type Value = BlendShapes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("list of blend shapes")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut shapes = BlendShapes::default();
+                while let Some(shape) = seq.next_element::<ShapeRef<'de>>()? {
+                    shapes.insert(&shape.k, shape.v);
+                }
+                Ok(shapes)
+            }
+        }
+
+        deserializer.deserialize_seq(ShapesVisitor)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct TrackingResponce {
     pub timestamp: u64,
@@ -32,54 +300,451 @@ pub struct TrackingResponce {
     pub rotation: Cords,
     pub position: Cords,
     pub eye_left: Cords,
-    pub blend_shapes: Vec<Shape>,
+    pub blend_shapes: BlendShapes,
+    /// Local time when frame was received, not sent over network
+    #[serde(skip)]
+    pub received_at: Option<time::Instant>,
+}
+
+/// Timestamp for frames produced locally, milliseconds since unix epoch
+pub(crate) fn now_millis() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Settings of tracking data requests to the phone
+#[derive(serde::Serialize, serde::Deserialize, clap::Args, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+#[command(about = None, next_help_heading = "Phone")]
+pub struct PhoneSettings {
+    /// Phone UDP port
+    #[arg(long = "phone-port", default_value_t = default_port())]
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// How long phone keeps streaming after each request
+    #[arg(long, default_value_t = default_send_for_seconds())]
+    #[serde(default = "default_send_for_seconds")]
+    pub send_for_seconds: u32,
+    /// Interval between requests in milliseconds
+    #[arg(long, default_value_t = default_request_interval_ms(), value_parser = clap::value_parser!(u64).range(1..))]
+    #[serde(default = "default_request_interval_ms")]
+    pub request_interval_ms: u64,
+    /// Read timeout in milliseconds
+    #[arg(long, default_value_t = default_read_timeout_ms(), value_parser = clap::value_parser!(u64).range(1..))]
+    #[serde(default = "default_read_timeout_ms")]
+    pub read_timeout_ms: u64,
+    /// Local UDP port for tracking data, 0 for any free port
+    #[arg(long, default_value_t = 0)]
+    #[serde(default)]
+    pub local_port: u16,
+    /// Phone is considered lost after this many milliseconds without data
+    #[arg(long, default_value_t = default_lost_timeout_ms())]
+    #[serde(default = "default_lost_timeout_ms")]
+    pub lost_timeout_ms: u64,
+    /// Max size of tracking datagram in bytes
+    #[arg(long, default_value_t = default_buffer_size(), value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+}
+
+fn default_port() -> u16 {
+    21412
+}
+
+fn default_send_for_seconds() -> u32 {
+    10
+}
+
+fn default_request_interval_ms() -> u64 {
+    1000
+}
+
+impl PhoneSettings {
+    /// Socket can't wait for tracking data without read timeout, zero interval would request after every datagram
+    /// and every datagram would be truncated in zero buffer
+    pub fn check(&self) -> Result<(), String> {
+        if self.read_timeout_ms == 0 {
+            return Err("readTimeoutMs: must be at least 1".to_string());
+        }
+        if self.request_interval_ms == 0 {
+            return Err("requestIntervalMs: must be at least 1".to_string());
+        }
+        if self.buffer_size == 0 {
+            return Err("bufferSize: must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn default_read_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_lost_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_buffer_size() -> usize {
+    4096
+}
+
+impl Default for PhoneSettings {
+    fn default() -> Self {
+        PhoneSettings {
+            port: default_port(),
+            send_for_seconds: default_send_for_seconds(),
+            request_interval_ms: default_request_interval_ms(),
+            read_timeout_ms: default_read_timeout_ms(),
+            local_port: 0,
+            lost_timeout_ms: default_lost_timeout_ms(),
+            buffer_size: default_buffer_size(),
+        }
+    }
+}
+
+/// Windows reports datagrams bigger than buffer as an error instead of truncating them
+const WSAEMSGSIZE: i32 = 10040;
+
+/// Phone address that selects first phone answering a broadcast request
+pub const AUTO_PHONE: &str = "auto";
+
+/// Tracking data request for the phone app, frames are sent to `port`
+pub(crate) fn tracking_request(settings: &PhoneSettings, port: u16) -> String {
+    serde_json::json!({
+        "messageType":"iOSTrackingDataRequest",
+        "sentBy": "RustyBridge",
+        "sendForSeconds": settings.send_for_seconds,
+        "ports": [port]
+    })
+    .to_string()
+}
+
+/// Resolve IP literal (v4 or v6) or hostname (`iphone.local`), preferring given family
+pub fn resolve(host: &str, port: u16, ipv6: bool) -> Result<SocketAddr, String> {
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("{}: {}", host, e))?
+        .collect();
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv6() == ipv6)
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| format!("{}: no address", host))
 }
 
 pub struct VtsPhone;
 
 impl VtsPhone {
-    pub fn run(ip: String, sender: Sender<TrackingResponce>, active: Arc<AtomicBool>) {
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        let _ = socket.set_read_timeout(Some(time::Duration::new(2, 0)));
-        let port = socket.local_addr().unwrap().port();
-
-        let mut buf = [0; 4096];
-
-        let request_traking: String = serde_json::json!({
-            "messageType":"iOSTrackingDataRequest",
-            "sentBy": "RustyBridge",
-            "sendForSeconds": 10,
-            "ports": [port]
-        })
-        .to_string();
+    /// Find phones on LAN (IPv4) that answer a broadcast tracking request
+    pub fn discover(
+        settings: &PhoneSettings,
+        duration: time::Duration,
+    ) -> Result<Vec<IpAddr>, String> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, settings.local_port))
+            .map_err(|e| e.to_string())?;
+        socket.set_broadcast(true).map_err(|e| e.to_string())?;
+        socket
+            .set_read_timeout(Some(time::Duration::from_millis(100)))
+            .map_err(|e| e.to_string())?;
+        let port = socket.local_addr().map_err(|e| e.to_string())?.port();
+
+        // Phones don't need to keep streaming after discovery
+        let request = tracking_request(
+            &PhoneSettings {
+                send_for_seconds: 1,
+                ..settings.clone()
+            },
+            port,
+        );
+        let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, settings.port));
+        let request_interval = time::Duration::from_millis(settings.request_interval_ms);
+
+        let mut buf = vec![0; settings.buffer_size + 1];
+        let mut phones: Vec<IpAddr> = Vec::new();
+        let end = time::Instant::now() + duration;
+        let mut next_time = time::Instant::now();
+
+        while time::Instant::now() < end {
+            if next_time <= time::Instant::now() {
+                next_time = time::Instant::now() + request_interval;
+                socket
+                    .send_to(request.as_bytes(), broadcast)
+                    .map_err(|e| e.to_string())?;
+            }
+
+            match socket.recv_from(&mut buf) {
+                Ok((amt, src)) => {
+                    let is_tracking =
+                        serde_json::from_slice::<TrackingResponce>(&buf[..amt]).is_ok();
+                    if is_tracking && !phones.contains(&src.ip()) {
+                        info!("Found phone at {}", src.ip());
+                        phones.push(src.ip());
+                    }
+                }
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut
+                        || error.raw_os_error() == Some(WSAEMSGSIZE) => {}
+                Err(error) => return Err(error.to_string()),
+            }
+        }
+
+        Ok(phones)
+    }
 
+    /// Socket for tracking data of the phone address family, with tracking request for its port
+    fn bind(
+        settings: &PhoneSettings,
+        ipv6: bool,
+        broadcast: bool,
+    ) -> io::Result<(UdpSocket, String)> {
+        let local: IpAddr = if ipv6 {
+            Ipv6Addr::UNSPECIFIED.into()
+        } else {
+            Ipv4Addr::UNSPECIFIED.into()
+        };
+
+        let socket = UdpSocket::bind((local, settings.local_port))?;
+        socket.set_read_timeout(Some(time::Duration::from_millis(settings.read_timeout_ms)))?;
+        if broadcast {
+            socket.set_broadcast(true)?;
+        }
+        let port = socket.local_addr()?.port();
+        info!("Receiving tracking data on port:{}", port);
+        Ok((socket, tracking_request(settings, port)))
+    }
+
+    /// `ip` is IP, hostname or `auto` to use first phone found on LAN
+    pub fn run(
+        ip: String,
+        settings: PhoneSettings,
+        sender: SlotSender<TrackingResponce>,
+        active: Arc<AtomicBool>,
+        monitor: PhoneMonitor,
+    ) {
+        let auto = ip == AUTO_PHONE;
+        // IPv4 until phone resolves only to IPv6
+        let mut ipv6 = false;
+        let (mut socket, mut request_traking) = match VtsPhone::bind(&settings, ipv6, auto) {
+            Ok(bound) => bound,
+            Err(error) => {
+                error!("Unable to bind tracking socket: {}", error);
+                return;
+            }
+        };
+
+        // One extra byte to detect datagrams that don't fit
+        let mut buf = vec![0; settings.buffer_size + 1];
+
+        let mut tracker = PhoneTracker::new(
+            ip.clone(),
+            monitor,
+            time::Duration::from_millis(settings.read_timeout_ms),
+            time::Duration::from_millis(settings.lost_timeout_ms),
+        );
+
+        let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, settings.port));
+        let mut phone_addr: Option<SocketAddr> = None;
+        let request_interval = time::Duration::from_millis(settings.request_interval_ms);
         let mut next_time = time::Instant::now();
+        // Reported once, phone sends the same keys in every frame
+        let mut unknown_keys: HashSet<String> = HashSet::new();
+        // Reported once, sources that are not the phone
+        let mut foreign: HashSet<IpAddr> = HashSet::new();
 
         while active.load(Ordering::Relaxed) {
             if next_time <= time::Instant::now() {
-                next_time = time::Instant::now() + time::Duration::from_secs(1);
+                next_time = time::Instant::now() + request_interval;
 
-                match socket.send_to(request_traking.as_bytes(), format!("{:}:21412", ip)) {
-                    Ok(_) => {
-                        // nice
+                if auto {
+                    if phone_addr.is_some() && tracker.state() == PhoneState::Lost {
+                        info!("Phone lost, searching again");
+                        phone_addr = None;
                     }
-                    Err(error) => {
-                        warn!("Unable to request tracking data: {}", error) // Maybe reconnect
+                } else if phone_addr.is_none() || tracker.state() == PhoneState::Lost {
+                    // Resolving can take seconds (mDNS), so only until the phone is found
+                    // and after it's lost, to pick up address changes
+                    match resolve(&ip, settings.port, ipv6) {
+                        Ok(addr) => {
+                            if addr.is_ipv6() != ipv6 {
+                                // Old socket can hold the local port
+                                drop(socket);
+                                (socket, request_traking) =
+                                    match VtsPhone::bind(&settings, addr.is_ipv6(), auto) {
+                                        Ok(bound) => {
+                                            ipv6 = addr.is_ipv6();
+                                            bound
+                                        }
+                                        Err(error) => {
+                                            warn!("Unable to bind tracking socket: {}", error);
+                                            match VtsPhone::bind(&settings, ipv6, auto) {
+                                                Ok(bound) => bound,
+                                                Err(error) => {
+                                                    error!(
+                                                        "Unable to bind tracking socket: {}",
+                                                        error
+                                                    );
+                                                    return;
+                                                }
+                                            }
+                                        }
+                                    };
+                            }
+                            if phone_addr != Some(addr) {
+                                info!("Phone {} is at {}", ip, addr);
+                            }
+                            phone_addr = Some(addr);
+                        }
+                        Err(error) => warn!("Unable to resolve phone address: {}", error),
+                    }
+                }
+
+                if let Some(addr) = phone_addr.or(auto.then_some(broadcast)) {
+                    match socket.send_to(request_traking.as_bytes(), addr) {
+                        Ok(_) => {
+                            // nice
+                        }
+                        Err(error) => {
+                            warn!("Unable to request tracking data: {}", error) // Maybe reconnect
+                        }
                     }
                 }
             }
 
             match socket.recv_from(&mut buf) {
-                Ok((amt, _src)) => match serde_json::from_slice::<TrackingResponce>(&buf[..amt]) {
-                    Ok(data) => sender.send(data).unwrap(),
+                // Only the phone may send frames, anyone on LAN can send datagrams
+                Ok((_, src)) if phone_addr.map_or(!auto, |addr| addr.ip() != src.ip()) => {
+                    // Another phone answering the broadcast is expected
+                    if !auto && foreign.insert(src.ip()) {
+                        warn!(
+                            "Ignoring tracking data from {}, it's not the phone",
+                            src.ip()
+                        );
+                    }
+                }
+                Ok((amt, _src)) if amt > settings.buffer_size => {
+                    warn!(
+                        "Tracking data truncated, datagram is bigger than buffer size: {}",
+                        settings.buffer_size
+                    )
+                }
+                Ok((amt, src)) => match serde_json::from_slice::<TrackingResponce>(&buf[..amt]) {
+                    Ok(mut data) => {
+                        if auto && phone_addr.is_none() {
+                            info!("Found phone at {}", src.ip());
+                            phone_addr = Some(SocketAddr::new(src.ip(), settings.port));
+                        }
+                        for shape in &data.blend_shapes.custom {
+                            if unknown_keys.insert(shape.k.clone()) {
+                                warn!("{} from phone", shape.k.parse::<BlendShape>().unwrap_err());
+                            }
+                        }
+                        // Older than already forwarded frame
+                        if tracker.packet(data.timestamp) {
+                            data.received_at = Some(time::Instant::now());
+                            if sender.send(data).is_err() {
+                                return;
+                            }
+                        }
+                    }
                     Err(error) => {
-                        warn!("Unnable to deserialize: {}", error)
+                        warn!("Unnable to deserialize: {}", error);
+                        tracker.deserialize_failed(error.to_string());
                     }
                 },
+                Err(error) if error.raw_os_error() == Some(WSAEMSGSIZE) => {
+                    warn!(
+                        "Tracking data truncated, datagram is bigger than buffer size: {}",
+                        settings.buffer_size
+                    )
+                }
+                Err(error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut =>
+                {
+                    // No data, state is updated by tracker
+                }
                 Err(error) => {
                     warn!("Unnable to receive: {}", error) // Maybe reconnect
                 }
             }
+
+            tracker.tick();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_name_suggests_closest() {
+        let shapes = || BlendShape::ALL.iter().map(|shape| shape.name());
+
+        assert_eq!(
+            unknown_name("blend shape", "jawopen", shapes()),
+            "Unknown blend shape `jawopen`, did you mean `JawOpen`?"
+        );
+        assert_eq!(
+            unknown_name("blend shape", "MouthSmileLetf", shapes()),
+            "Unknown blend shape `MouthSmileLetf`, did you mean `MouthSmileLeft`?"
+        );
+        assert_eq!(
+            unknown_name("blend shape", "Eyebrows", shapes()),
+            "Unknown blend shape `Eyebrows`"
+        );
+        assert_eq!(
+            "JawOpn".parse::<BlendShape>().unwrap_err(),
+            "Unknown blend shape `JawOpn`, did you mean `JawOpen`?"
+        );
+        assert_eq!("CheekPuff".parse::<BlendShape>(), Ok(BlendShape::CheekPuff));
+    }
+
+    #[test]
+    fn distance_counts_edits() {
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("jawopen", "jawopen"), 0);
+    }
+
+    #[test]
+    fn blend_shapes_from_phone() {
+        // escaped name can't be borrowed
+        let json = r#"[{"k":"Jaw\u004fpen","v":0.5},{"k":"EyeBlinkLeft","v":1.0},{"k":"tongueOut","v":0.25}]"#;
+        let shapes: BlendShapes = serde_json::from_str(json).unwrap();
+
+        assert_eq!(shapes.len(), 3);
+        assert_eq!(shapes.get(BlendShape::JawOpen), Some(0.5));
+        assert_eq!(shapes.get(BlendShape::EyeBlinkLeft), Some(1.0));
+        assert_eq!(shapes.get(BlendShape::EyeBlinkRight), None);
+        assert_eq!(shapes.custom.len(), 1);
+        assert_eq!(shapes.custom[0].k, "tongueOut");
+    }
+
+    #[test]
+    fn blend_shapes_round_trip() {
+        let mut shapes = BlendShapes::default();
+        shapes.insert("Custom", 2.0);
+        shapes.set(BlendShape::MouthClose, 0.1);
+        shapes.insert("JawOpen", 0.5);
+        shapes.insert("Custom", 3.0);
+
+        // ARKit shapes in their order, then custom ones
+        let json = serde_json::to_string(&shapes).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"k":"JawOpen","v":0.5},{"k":"MouthClose","v":0.1},{"k":"Custom","v":3.0}]"#
+        );
+
+        let parsed: BlendShapes = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            parsed.iter().collect::<Vec<_>>(),
+            shapes.iter().collect::<Vec<_>>()
+        );
+        assert!(serde_json::from_str::<BlendShapes>(r#"{"k":"JawOpen","v":0.5}"#).is_err());
+    }
+}
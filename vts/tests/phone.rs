@@ -0,0 +1,77 @@
+use std::{
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use rusty_bridge_lib::{
+    health::PhoneMonitor,
+    mockphone::{MockFrames, MockPhone},
+    slot::slot,
+    vtsphone::{BlendShape, PhoneSettings, VtsPhone},
+};
+
+/// Free UDP port on loopback
+fn free_port() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().port()
+}
+
+#[test]
+fn mock_phone_streams_synthetic_frames() {
+    let port = free_port();
+    let active = Arc::new(AtomicBool::new(true));
+
+    let flag = Arc::clone(&active);
+    let phone = thread::spawn(move || {
+        MockPhone::run(port, MockFrames::Synthetic { fps: 60 }, flag).unwrap();
+    });
+
+    let (sender, receiver) = slot();
+    let settings = PhoneSettings {
+        port,
+        request_interval_ms: 100,
+        read_timeout_ms: 100,
+        ..Default::default()
+    };
+    let flag = Arc::clone(&active);
+    let bridge = thread::spawn(move || {
+        VtsPhone::run(
+            "127.0.0.1".to_string(),
+            settings,
+            sender,
+            flag,
+            PhoneMonitor::default(),
+        )
+    });
+
+    let end = time::Instant::now() + time::Duration::from_secs(5);
+    let frame = loop {
+        if let Some(frame) = receiver.try_recv() {
+            break Some(frame);
+        }
+        if time::Instant::now() > end {
+            break None;
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    };
+
+    active.store(false, Ordering::Relaxed);
+    let _ = phone.join();
+    let _ = bridge.join();
+
+    let frame = frame.expect("no frame from mock phone");
+    assert!(frame.face_found);
+    for shape in BlendShape::ALL {
+        assert!(
+            frame.blend_shapes.get(shape).is_some(),
+            "{} missing",
+            shape.name()
+        );
+    }
+    let jaw_open = frame.blend_shapes.get(BlendShape::JawOpen).unwrap();
+    assert!((0.0..=0.6).contains(&jaw_open));
+}
@@ -0,0 +1,84 @@
+use std::{
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use rusty_bridge_lib::{
+    relay::Relay,
+    slot::{slot, SlotReceiver},
+    tunnel::Tunnel,
+    vtsphone::{BlendShapes, Cords, TrackingResponce},
+};
+
+/// Free UDP port on loopback
+fn free_port() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().port()
+}
+
+fn frame(timestamp: u64) -> TrackingResponce {
+    TrackingResponce {
+        timestamp,
+        hotkey: -1,
+        face_found: true,
+        rotation: Cords::default(),
+        position: Cords::default(),
+        eye_left: Cords::default(),
+        blend_shapes: BlendShapes::default(),
+        received_at: None,
+    }
+}
+
+fn next_frame(receiver: &SlotReceiver<TrackingResponce>) -> Option<TrackingResponce> {
+    let end = time::Instant::now() + time::Duration::from_secs(5);
+    while time::Instant::now() < end {
+        if let Some(frame) = receiver.try_recv() {
+            return Some(frame);
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    }
+    None
+}
+
+#[test]
+fn replay_from_other_address_is_rejected() {
+    let port = free_port();
+    let active = Arc::new(AtomicBool::new(true));
+
+    let (sender, receiver) = slot();
+    let flag = Arc::clone(&active);
+    let listener = thread::spawn(move || {
+        Relay::listen(port, Some("secret".to_string()), sender, flag);
+    });
+
+    let mut tunnel = Tunnel::new("secret");
+    let packet = tunnel.seal(&serde_json::to_vec(&frame(1)).unwrap());
+    let next = tunnel.seal(&serde_json::to_vec(&frame(2)).unwrap());
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.send_to(&packet, ("127.0.0.1", port)).unwrap();
+    let received = next_frame(&receiver).expect("sealed frame not received");
+    assert_eq!(received.timestamp, 1);
+
+    // same packet from another port and another loopback address
+    let other_port = UdpSocket::bind("127.0.0.1:0").unwrap();
+    other_port.send_to(&packet, ("127.0.0.1", port)).unwrap();
+    if let Ok(other_ip) = UdpSocket::bind("127.0.0.2:0") {
+        other_ip.send_to(&packet, ("127.0.0.1", port)).unwrap();
+    }
+    thread::sleep(time::Duration::from_millis(500));
+    let replayed = receiver.try_recv();
+
+    // listener still accepts new frames
+    socket.send_to(&next, ("127.0.0.1", port)).unwrap();
+    let received = next_frame(&receiver);
+    active.store(false, Ordering::Relaxed);
+    let _ = listener.join();
+
+    assert!(replayed.is_none(), "replayed frame was accepted");
+    assert_eq!(received.expect("next frame not received").timestamp, 2);
+}